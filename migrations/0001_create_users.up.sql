@@ -0,0 +1,26 @@
+-- IF NOT EXISTS adopts databases created before migrations existed.
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    email VARCHAR NOT NULL
+);
+-- Such a database may hold emails that differ only in case, which the index
+-- below cannot be built over. Name them instead of failing on the first pair.
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(emails, '; ') INTO duplicates
+    FROM (
+        SELECT string_agg(format('%s (id %s)', email, id), ', ' ORDER BY id) AS emails
+        FROM users
+        GROUP BY LOWER(email)
+        HAVING COUNT(*) > 1
+    ) AS conflicts;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'emails must be unique regardless of case, but these users share one: %', duplicates
+            USING HINT = 'Change or delete all but one user of each group, then migrate again.';
+    END IF;
+END
+$$;
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (LOWER(email));
@@ -0,0 +1,4 @@
+-- pg_trgm is left installed; other schemas may depend on it.
+DROP INDEX users_email_trgm_idx;
+DROP INDEX users_name_trgm_idx;
+DROP INDEX users_search_document_idx;
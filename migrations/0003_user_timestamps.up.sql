@@ -0,0 +1,4 @@
+-- Existing rows get the migration time; their real history is unknown.
+ALTER TABLE users
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
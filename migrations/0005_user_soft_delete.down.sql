@@ -0,0 +1,6 @@
+-- Tombstones cannot be represented without the column, so they are purged.
+DELETE FROM users WHERE deleted_at IS NOT NULL;
+DROP INDEX users_deleted_at_idx;
+DROP INDEX users_email_lower_key;
+CREATE UNIQUE INDEX users_email_lower_key ON users (LOWER(email));
+ALTER TABLE users DROP COLUMN deleted_at;
@@ -0,0 +1,15 @@
+-- One row per version of every user. Purging a user removes its history too;
+-- the audit log keeps a record that it existed.
+CREATE TABLE users_history (
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    name VARCHAR NOT NULL,
+    email VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    deleted_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, version)
+);
+-- Earlier versions were never kept; history starts with the current one.
+INSERT INTO users_history (user_id, version, name, email, created_at, updated_at, deleted_at)
+    SELECT id, version, name, email, created_at, updated_at, deleted_at FROM users;
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use serde_json::{json, Map, Value};
+use crate::error::AppError;
+use crate::models::User;
+use crate::request::Request;
+
+pub const DEFAULT_AUDIT_LIMIT: i64 = 100;
+pub const MAX_AUDIT_LIMIT: i64 = 1000;
+const MAX_ACTOR_LENGTH: usize = 200;
+
+/// Fields whose changes are recorded; bookkeeping like `version` is left out.
+const AUDITED_FIELDS: [&str; 3] = ["name", "email", "deleted_at"];
+
+/// Who is performing a mutation, recorded with it in the audit log.
+pub struct AuditContext {
+    pub actor: String,
+    pub request_id: String,
+}
+
+impl AuditContext {
+    /// There is no authentication yet, so the actor is whatever the caller
+    /// claims in `X-Actor`.
+    pub fn from_request(request: &Request) -> AuditContext {
+        let actor = request
+            .header("X-Actor")
+            .map(str::trim)
+            .filter(|actor| !actor.is_empty())
+            .unwrap_or("anonymous");
+        AuditContext {
+            actor: actor.chars().take(MAX_ACTOR_LENGTH).collect(),
+            request_id: request.id.clone(),
+        }
+    }
+
+    /// For mutations the server makes on its own, e.g. purging tombstones.
+    pub fn system(request_id: String) -> AuditContext {
+        AuditContext {
+            actor: "system".to_string(),
+            request_id,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+            AuditAction::Restore => "restore",
+            AuditAction::Purge => "purge",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user_id: i32,
+    pub action: String,
+    pub actor: String,
+    pub request_id: String,
+    /// `{"field": {"before": ..., "after": ...}}` for every audited field that changed.
+    pub changes: Value,
+    pub occurred_at: DateTime<Utc>,
+}
+
+/// Diffs the audited fields of a user before and after a mutation; `None`
+/// stands for a user that does not exist on that side.
+pub fn changes(before: Option<&User>, after: Option<&User>) -> Value {
+    let as_object = |user: Option<&User>| match user.map(serde_json::to_value) {
+        Some(Ok(Value::Object(object))) => object,
+        _ => Map::new(),
+    };
+    let (before, after) = (as_object(before), as_object(after));
+    let mut changes = Map::new();
+    for field in AUDITED_FIELDS {
+        let old = before.get(field).unwrap_or(&Value::Null);
+        let new = after.get(field).unwrap_or(&Value::Null);
+        if old != new {
+            changes.insert(field.to_string(), json!({ "before": old, "after": new }));
+        }
+    }
+    Value::Object(changes)
+}
+
+/// The query string of the audit endpoints.
+pub struct AuditQuery {
+    /// Only entries at or after this instant.
+    pub since: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+impl AuditQuery {
+    pub fn from_request(request: &Request) -> Result<AuditQuery, AppError> {
+        let mut query = AuditQuery {
+            since: None,
+            limit: DEFAULT_AUDIT_LIMIT,
+        };
+        for (name, value) in request.query_pairs() {
+            let invalid = |detail: &str| AppError::InvalidQueryParameter {
+                name: name.clone(),
+                detail: detail.to_string(),
+            };
+            match name.as_str() {
+                "since" => {
+                    query.since = Some(parse_timestamp(&value).ok_or_else(|| invalid("must be an RFC 3339 timestamp"))?)
+                }
+                "limit" => match value.parse::<i64>() {
+                    Ok(limit) if limit >= 1 => query.limit = limit.min(MAX_AUDIT_LIMIT),
+                    _ => return Err(invalid("must be a positive integer")),
+                },
+                _ => return Err(invalid("unknown query parameter")),
+            }
+        }
+        Ok(query)
+    }
+}
+
+/// Parses an RFC 3339 timestamp, forgiving an unencoded `+` in the offset
+/// that query string decoding turned into a space.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_rfc3339(&value.replace(' ', "+")))
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
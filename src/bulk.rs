@@ -0,0 +1,142 @@
+use serde_json::{Map, Value};
+use crate::error::AppError;
+use crate::models::NewUser;
+use crate::request::Request;
+use crate::validation::{validate_user_object, FieldError};
+
+pub const DEFAULT_MAX_BULK_OPERATIONS: usize = 1000;
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// One item of a `POST /users/bulk` batch. `version` makes an update or
+/// delete conditional, like `If-Match` on the single-user endpoints.
+pub enum BulkOperation {
+    Create(NewUser),
+    Update { id: i32, user: NewUser, version: Option<i32> },
+    Delete { id: i32, version: Option<i32> },
+}
+
+impl BulkOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BulkOperation::Create(_) => "create",
+            BulkOperation::Update { .. } => "update",
+            BulkOperation::Delete { .. } => "delete",
+        }
+    }
+
+    /// An update or delete without `version`, which strict mode refuses.
+    pub fn is_unconditional(&self) -> bool {
+        matches!(
+            self,
+            BulkOperation::Update { version: None, .. } | BulkOperation::Delete { version: None, .. }
+        )
+    }
+}
+
+/// Splits a bulk body, a JSON array or one operation per line of NDJSON, into
+/// its operations. Each is parsed on its own, so one bad item does not reject
+/// the whole batch.
+pub fn parse_operations(request: &Request, max: usize) -> Result<Vec<Result<BulkOperation, AppError>>, AppError> {
+    let content_type = request
+        .header("Content-Type")
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    // Like the other endpoints, anything but NDJSON is read as JSON.
+    let items: Vec<Result<Value, AppError>> = match content_type.as_deref() {
+        Some(NDJSON_CONTENT_TYPE) => request
+            .body
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).map_err(AppError::from))
+            .collect(),
+        _ => match serde_json::from_slice(&request.body)? {
+            Value::Array(items) => items.into_iter().map(Ok).collect(),
+            _ => return Err(AppError::InvalidPayload("expected a JSON array of operations".to_string())),
+        },
+    };
+    if items.is_empty() {
+        return Err(AppError::InvalidPayload("the batch has no operations".to_string()));
+    }
+    if items.len() > max {
+        return Err(AppError::BatchTooLarge { max });
+    }
+    Ok(items.into_iter().map(|item| item.and_then(parse_operation)).collect())
+}
+
+fn parse_operation(value: Value) -> Result<BulkOperation, AppError> {
+    let Value::Object(object) = value else {
+        return Err(AppError::Validation(vec![FieldError::new("", "operation must be a JSON object")]));
+    };
+    let op = match object.get("op") {
+        Some(Value::String(op)) if ["create", "update", "delete"].contains(&op.as_str()) => op.as_str(),
+        Some(_) => return Err(AppError::Validation(vec![FieldError::new("op", "must be one of create, update, delete")])),
+        None => return Err(AppError::Validation(vec![FieldError::new("op", "is required")])),
+    };
+    let allowed: &[&str] = match op {
+        "create" => &["op", "user"],
+        "update" => &["op", "id", "version", "user"],
+        _ => &["op", "id", "version"],
+    };
+    let mut errors = Vec::new();
+    for key in object.keys().filter(|key| !allowed.contains(&key.as_str())) {
+        match key.as_str() {
+            "id" | "version" | "user" => errors.push(FieldError::new(key, format!("not allowed in a {} operation", op))),
+            _ => errors.push(FieldError::new(key, "unknown field")),
+        }
+    }
+
+    let id = allowed.contains(&"id").then(|| positive_integer(&object, "id", true, &mut errors)).flatten();
+    let version = allowed
+        .contains(&"version")
+        .then(|| positive_integer(&object, "version", false, &mut errors))
+        .flatten();
+    let user = match object.get("user") {
+        Some(Value::Object(user)) if allowed.contains(&"user") => match validate_user_object(user) {
+            Ok(user) => Some(user),
+            Err(AppError::Validation(user_errors)) => {
+                errors.extend(user_errors.into_iter().map(|e| FieldError {
+                    field: format!("user.{}", e.field).trim_end_matches('.').to_string(),
+                    message: e.message,
+                }));
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        Some(_) if allowed.contains(&"user") => {
+            errors.push(FieldError::new("user", "must be a JSON object"));
+            None
+        }
+        None if allowed.contains(&"user") => {
+            errors.push(FieldError::new("user", "is required"));
+            None
+        }
+        _ => None,
+    };
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    match (op, id, user) {
+        ("create", _, Some(user)) => Ok(BulkOperation::Create(user)),
+        ("update", Some(id), Some(user)) => Ok(BulkOperation::Update { id, user, version }),
+        ("delete", Some(id), _) => Ok(BulkOperation::Delete { id, version }),
+        _ => Err(AppError::Internal),
+    }
+}
+
+fn positive_integer(object: &Map<String, Value>, field: &str, required: bool, errors: &mut Vec<FieldError>) -> Option<i32> {
+    match object.get(field) {
+        Some(value) => match value.as_i64().and_then(|n| i32::try_from(n).ok()) {
+            Some(n) if n >= 1 => Some(n),
+            _ => {
+                errors.push(FieldError::new(field, "must be a positive integer"));
+                None
+            }
+        },
+        None if required => {
+            errors.push(FieldError::new(field, "is required"));
+            None
+        }
+        None => None,
+    }
+}
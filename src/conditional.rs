@@ -0,0 +1,74 @@
+use chrono::{DateTime, Utc};
+use crate::request::Request;
+use crate::utils::fnv1a_64;
+
+/// The parsed `If-Match` header of a write.
+pub enum IfMatch {
+    Absent,
+    Any,
+    /// Versions named by the listed tags. Weak and foreign tags are dropped,
+    /// since `If-Match` uses the strong comparison.
+    Versions(Vec<i32>),
+}
+
+impl IfMatch {
+    pub fn from_request(request: &Request) -> IfMatch {
+        match request.header("If-Match").map(str::trim) {
+            None => IfMatch::Absent,
+            Some("*") => IfMatch::Any,
+            Some(tags) => IfMatch::Versions(
+                tags.split(',')
+                    .filter_map(|tag| tag.trim().strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The versions a write must find, or `None` if any version will do.
+    pub fn versions(&self) -> Option<&[i32]> {
+        match self {
+            IfMatch::Versions(versions) => Some(versions),
+            IfMatch::Absent | IfMatch::Any => None,
+        }
+    }
+}
+
+/// The strong entity tag of a single user, which changes with every write.
+pub fn version_etag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+/// A strong entity tag for a response body: it changes whenever a byte does.
+pub fn body_etag(body: &str) -> String {
+    format!("\"{:016x}\"", fnv1a_64(body.as_bytes()))
+}
+
+/// Formats `time` as an IMF-fixdate, the only date format servers may send.
+pub fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether a GET can be answered with 304 Not Modified (RFC 9110 section 13.2.2).
+///
+/// `If-None-Match` takes precedence; `If-Modified-Since` is only consulted
+/// without it, and at the one second resolution HTTP dates have.
+pub fn not_modified(request: &Request, etag: &str, last_modified: Option<&DateTime<Utc>>) -> bool {
+    if let Some(candidates) = request.header("If-None-Match") {
+        return candidates.trim() == "*"
+            || candidates
+                .split(',')
+                .any(|candidate| opaque_tag(candidate) == opaque_tag(etag));
+    }
+    match (request.header("If-Modified-Since"), last_modified) {
+        (Some(since), Some(modified)) => DateTime::parse_from_rfc2822(since.trim())
+            .map(|since| modified.timestamp() <= since.timestamp())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// `If-None-Match` uses the weak comparison, which ignores the `W/` prefix.
+fn opaque_tag(tag: &str) -> &str {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
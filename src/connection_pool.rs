@@ -0,0 +1,298 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use postgres::{Client, Config as ClientConfig, NoTls};
+use postgres::Error as PostgresError;
+use serde_derive::Serialize;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy)]
+pub struct PoolConfig {
+    pub min_size: usize,
+    pub max_size: usize,
+    /// Connections above `min_size` that sit unused this long are closed.
+    pub idle_timeout: Duration,
+    /// How long a checkout waits for a free connection before giving up.
+    pub checkout_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            min_size: 1,
+            max_size: 10,
+            idle_timeout: Duration::from_secs(300),
+            checkout_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    Connect(PostgresError),
+    /// Every connection stayed checked out for the whole checkout timeout.
+    Exhausted,
+    /// The server is shutting down.
+    Closed,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Connect(e) => write!(f, "failed to open database connection: {}", e),
+            PoolError::Exhausted => write!(f, "timed out waiting for a database connection"),
+            PoolError::Closed => write!(f, "the connection pool is closed"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PoolStatus {
+    pub size: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    pub max_size: usize,
+    pub connections_created: u64,
+    pub checkouts: u64,
+    pub checkout_waits: u64,
+    pub exhausted: u64,
+    pub failed_health_checks: u64,
+}
+
+#[derive(Default)]
+struct PoolMetrics {
+    connections_created: AtomicU64,
+    checkouts: AtomicU64,
+    checkout_waits: AtomicU64,
+    exhausted: AtomicU64,
+    failed_health_checks: AtomicU64,
+}
+
+struct IdleConnection {
+    client: Client,
+    idle_since: Instant,
+}
+
+struct PoolState {
+    idle: Vec<IdleConnection>,
+    /// Idle plus checked out plus currently being opened.
+    total: usize,
+    closed: bool,
+}
+
+pub struct ConnectionPool {
+    client_config: ClientConfig,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    available: Condvar,
+    metrics: PoolMetrics,
+}
+
+impl ConnectionPool {
+    /// Creates the pool and eagerly opens `min_size` connections.
+    pub fn new(db_url: &str, config: PoolConfig) -> Result<ConnectionPool, PoolError> {
+        let pool = ConnectionPool {
+            client_config: db_url.parse().map_err(PoolError::Connect)?,
+            config,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                total: 0,
+                closed: false,
+            }),
+            available: Condvar::new(),
+            metrics: PoolMetrics::default(),
+        };
+
+        let mut idle = Vec::with_capacity(config.min_size);
+        for _ in 0..config.min_size {
+            idle.push(IdleConnection {
+                client: pool.connect(Some(config.checkout_timeout))?,
+                idle_since: Instant::now(),
+            });
+        }
+        {
+            let mut state = pool.lock_state();
+            state.total = idle.len();
+            state.idle = idle;
+        }
+        Ok(pool)
+    }
+
+    pub fn get(&self) -> Result<PooledConnection<'_>, PoolError> {
+        // Without a representable deadline, wait for as long as it takes.
+        let deadline = Instant::now().checked_add(self.config.checkout_timeout);
+        let mut waited = false;
+        let mut state = self.lock_state();
+
+        loop {
+            if state.closed {
+                return Err(PoolError::Closed);
+            }
+            self.reap_idle(&mut state);
+
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                let mut client = idle.client;
+                if client.is_valid(HEALTH_CHECK_TIMEOUT).is_ok() {
+                    return Ok(self.checked_out(client));
+                }
+                self.metrics.failed_health_checks.fetch_add(1, Ordering::Relaxed);
+                state = self.lock_state();
+                state.total -= 1;
+                continue;
+            }
+
+            if state.total < self.config.max_size {
+                state.total += 1;
+                drop(state);
+                let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                return match self.connect(remaining) {
+                    Ok(client) => Ok(self.checked_out(client)),
+                    Err(e) => {
+                        self.lock_state().total -= 1;
+                        self.available.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                self.metrics.exhausted.fetch_add(1, Ordering::Relaxed);
+                println!("Error: database connection pool exhausted ({} connections in use)", state.total);
+                return Err(PoolError::Exhausted);
+            }
+            if !waited {
+                waited = true;
+                self.metrics.checkout_waits.fetch_add(1, Ordering::Relaxed);
+            }
+            let remaining = deadline.map_or(Duration::MAX, |deadline| deadline - now);
+            state = match self.available.wait_timeout(state, remaining) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+    }
+
+    /// Closes the idle connections, and every checked out one as it comes back.
+    /// Checkouts fail from now on.
+    pub fn close(&self) {
+        let idle = {
+            let mut state = self.lock_state();
+            state.closed = true;
+            state.total -= state.idle.len();
+            std::mem::take(&mut state.idle)
+        };
+        self.available.notify_all();
+        for idle in idle {
+            if let Err(e) = idle.client.close() {
+                println!("Error closing database connection: {}", e);
+            }
+        }
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let state = self.lock_state();
+        PoolStatus {
+            size: state.total,
+            idle: state.idle.len(),
+            in_use: state.total - state.idle.len(),
+            max_size: self.config.max_size,
+            connections_created: self.metrics.connections_created.load(Ordering::Relaxed),
+            checkouts: self.metrics.checkouts.load(Ordering::Relaxed),
+            checkout_waits: self.metrics.checkout_waits.load(Ordering::Relaxed),
+            exhausted: self.metrics.exhausted.load(Ordering::Relaxed),
+            failed_health_checks: self.metrics.failed_health_checks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Opens a connection. Reaching the server may take `timeout` or the URL's
+    /// own `connect_timeout`, whichever is shorter.
+    fn connect(&self, timeout: Option<Duration>) -> Result<Client, PoolError> {
+        let mut config = self.client_config.clone();
+        let timeout = match (timeout, config.get_connect_timeout()) {
+            (Some(timeout), Some(&own)) => Some(timeout.min(own)),
+            (timeout, own) => timeout.or(own.copied()),
+        };
+        if let Some(timeout) = timeout {
+            config.connect_timeout(timeout);
+        }
+        let client = config.connect(NoTls).map_err(PoolError::Connect)?;
+        self.metrics.connections_created.fetch_add(1, Ordering::Relaxed);
+        Ok(client)
+    }
+
+    fn checked_out(&self, client: Client) -> PooledConnection<'_> {
+        self.metrics.checkouts.fetch_add(1, Ordering::Relaxed);
+        PooledConnection {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    fn reap_idle(&self, state: &mut PoolState) {
+        let min_size = self.config.min_size;
+        let idle_timeout = self.config.idle_timeout;
+        let mut total = state.total;
+        // Oldest connections sit at the front since checkouts pop from the back.
+        state.idle.retain(|idle| {
+            if total > min_size && idle.idle_since.elapsed() >= idle_timeout {
+                total -= 1;
+                false
+            } else {
+                true
+            }
+        });
+        state.total = total;
+    }
+
+    fn release(&self, client: Client) {
+        let mut state = self.lock_state();
+        if client.is_closed() || state.closed {
+            state.total -= 1;
+        } else {
+            state.idle.push(IdleConnection {
+                client,
+                idle_since: Instant::now(),
+            });
+        }
+        drop(state);
+        self.available.notify_one();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, PoolState> {
+        // The state is only counters and a Vec, both valid even if a holder panicked.
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A checked out connection, returned to the pool when dropped.
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("connection already released")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("connection already released")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release(client);
+        }
+    }
+}
@@ -1,3 +1,4 @@
-pub const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
-pub const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\n\r\n";
-pub const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 Internal Server Error\r\n\r\n";
\ No newline at end of file
+pub const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
@@ -1,14 +1,255 @@
+use std::fmt;
 use postgres::{Client, NoTls};
 use postgres::Error as PostgresError;
+use crate::logging::{self, LogLevel};
+use crate::utils::fnv1a_64;
 
-pub fn set_database(db_url: &str) -> Result<(), PostgresError> {
-    let mut client = Client::connect(db_url, NoTls)?;
-    client.batch_execute("
-        CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            name VARCHAR NOT NULL,
-            email VARCHAR NOT NULL
-        )"
+/// Serialises migration runs across every process sharing the database.
+const MIGRATION_LOCK_KEY: i64 = 0x7573_6572_735f_6462;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $name, ".down.sql")),
+        }
+    };
+}
+
+/// Every migration, in the order it is applied. Never edit or reorder one that
+/// has shipped; its checksum is recorded and verified on every run.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create_users"),
+    migration!(2, "0002_users_search"),
+    migration!(3, "0003_user_timestamps"),
+    migration!(4, "0004_user_version"),
+    migration!(5, "0005_user_soft_delete"),
+    migration!(6, "0006_audit_log"),
+    migration!(7, "0007_users_history"),
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(PostgresError),
+    /// An applied migration's SQL no longer matches what was run.
+    ChecksumMismatch { version: i64, name: String },
+    /// The database has a migration this binary does not know, i.e. it was
+    /// migrated by a newer release.
+    UnknownVersion(i64),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "{}", e),
+            MigrationError::ChecksumMismatch { version, name } => {
+                write!(f, "migration {} ({}) was modified after it was applied", version, name)
+            }
+            MigrationError::UnknownVersion(version) => {
+                write!(f, "database has migration {} which this binary does not know", version)
+            }
+        }
+    }
+}
+
+impl From<PostgresError> for MigrationError {
+    fn from(e: PostgresError) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: &'static str,
+    pub state: MigrationState,
+}
+
+pub enum MigrationState {
+    Applied { applied_at: String },
+    Pending,
+    Modified { applied_at: String },
+    Unknown { applied_at: String },
+}
+
+struct AppliedMigration {
+    version: i64,
+    checksum: String,
+    applied_at: String,
+}
+
+/// Brings the schema up to date; run at startup before serving requests.
+pub fn set_database(db_url: &str) -> Result<(), MigrationError> {
+    let mut client = connect(db_url)?;
+    let applied = migrate_up(&mut client)?;
+    if logging::enabled(LogLevel::Info) {
+        for migration in applied {
+            println!("Applied migration {} ({})", migration.version, migration.name);
+        }
+    }
+    Ok(())
+}
+
+pub fn connect(db_url: &str) -> Result<Client, MigrationError> {
+    Ok(Client::connect(db_url, NoTls)?)
+}
+
+/// Applies every pending migration, each in its own transaction, and returns them.
+pub fn migrate_up(client: &mut Client) -> Result<Vec<&'static Migration>, MigrationError> {
+    with_migration_lock(client, |client| {
+        let applied = verify_applied(client)?;
+        let mut ran = Vec::new();
+        for migration in MIGRATIONS {
+            if applied.iter().any(|a| a.version == migration.version) {
+                continue;
+            }
+            let mut transaction = client.transaction()?;
+            transaction.batch_execute(migration.up)?;
+            transaction.execute(
+                "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+                &[&migration.version, &migration.name, &checksum(migration.up)],
+            )?;
+            transaction.commit()?;
+            ran.push(migration);
+        }
+        Ok(ran)
+    })
+}
+
+/// Reverts the `steps` most recently applied migrations, newest first.
+pub fn migrate_down(client: &mut Client, steps: usize) -> Result<Vec<&'static Migration>, MigrationError> {
+    with_migration_lock(client, |client| {
+        let applied = verify_applied(client)?;
+        let mut reverted = Vec::new();
+        for applied in applied.iter().rev().take(steps) {
+            let migration = find(applied.version).ok_or(MigrationError::UnknownVersion(applied.version))?;
+            let mut transaction = client.transaction()?;
+            transaction.batch_execute(migration.down)?;
+            transaction.execute("DELETE FROM schema_migrations WHERE version = $1", &[&migration.version])?;
+            transaction.commit()?;
+            reverted.push(migration);
+        }
+        Ok(reverted)
+    })
+}
+
+/// Reports the state of every known migration, followed by any applied ones
+/// this binary does not know.
+pub fn migration_status(client: &mut Client) -> Result<Vec<MigrationStatus>, MigrationError> {
+    with_migration_lock(client, |client| {
+        let applied = load_applied(client)?;
+        let mut statuses: Vec<MigrationStatus> = MIGRATIONS
+            .iter()
+            .map(|migration| {
+                let state = match applied.iter().find(|a| a.version == migration.version) {
+                    None => MigrationState::Pending,
+                    Some(a) if a.checksum == checksum(migration.up) => MigrationState::Applied {
+                        applied_at: a.applied_at.clone(),
+                    },
+                    Some(a) => MigrationState::Modified {
+                        applied_at: a.applied_at.clone(),
+                    },
+                };
+                MigrationStatus {
+                    version: migration.version,
+                    name: migration.name,
+                    state,
+                }
+            })
+            .collect();
+        statuses.extend(applied.iter().filter(|a| find(a.version).is_none()).map(|a| MigrationStatus {
+            version: a.version,
+            name: "?",
+            state: MigrationState::Unknown {
+                applied_at: a.applied_at.clone(),
+            },
+        }));
+        Ok(statuses)
+    })
+}
+
+fn with_migration_lock<T>(
+    client: &mut Client,
+    run: impl FnOnce(&mut Client) -> Result<T, MigrationError>,
+) -> Result<T, MigrationError> {
+    client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])?;
+    let result = client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                checksum TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .map_err(MigrationError::from)
+        .and_then(|_| run(client));
+    // The lock is released with the session anyway; unlocking early just lets
+    // a waiting process proceed sooner.
+    let unlocked = client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY]);
+    let value = result?;
+    unlocked?;
+    Ok(value)
+}
+
+/// Migrations this binary has that the database lacks or applied differently.
+/// Unlike [`migration_status`] it takes no lock, so it is cheap enough for
+/// readiness probes.
+pub fn outstanding_migrations(client: &mut Client) -> Result<Vec<&'static Migration>, MigrationError> {
+    let applied = load_applied(client)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| {
+            !applied
+                .iter()
+                .any(|a| a.version == migration.version && a.checksum == checksum(migration.up))
+        })
+        .collect())
+}
+
+fn load_applied(client: &mut Client) -> Result<Vec<AppliedMigration>, MigrationError> {
+    let rows = client.query(
+        "SELECT version, checksum, applied_at::text FROM schema_migrations ORDER BY version",
+        &[],
     )?;
-    Ok(()) 
-}
\ No newline at end of file
+    Ok(rows
+        .iter()
+        .map(|row| AppliedMigration {
+            version: row.get(0),
+            checksum: row.get(1),
+            applied_at: row.get(2),
+        })
+        .collect())
+}
+
+/// Loads the applied migrations, failing if any differs from this binary's copy.
+fn verify_applied(client: &mut Client) -> Result<Vec<AppliedMigration>, MigrationError> {
+    let applied = load_applied(client)?;
+    for a in &applied {
+        let migration = find(a.version).ok_or(MigrationError::UnknownVersion(a.version))?;
+        if a.checksum != checksum(migration.up) {
+            return Err(MigrationError::ChecksumMismatch {
+                version: migration.version,
+                name: migration.name.to_string(),
+            });
+        }
+    }
+    Ok(applied)
+}
+
+fn find(version: i64) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|migration| migration.version == version)
+}
+
+/// FNV-1a of the migration's SQL, as hex.
+fn checksum(sql: &str) -> String {
+    format!("{:016x}", fnv1a_64(sql.as_bytes()))
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use serde_json::{json, Value};
+use crate::constants::PROBLEM_JSON_CONTENT_TYPE;
+use crate::patch::PatchError;
+use crate::repository::RepositoryError;
+use crate::response::{reason_phrase, Response};
+use crate::router::ParamError;
+use crate::validation::FieldError;
+
+/// Every way a request can fail, rendered as an RFC 7807 problem document.
+///
+/// `code()` is part of the API contract: clients switch on it, so existing codes
+/// must never be renamed.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    MalformedJson(String),
+    InvalidPatch(String),
+    InvalidPathParameter(ParamError),
+    InvalidQueryParameter { name: String, detail: String },
+    RouteNotFound,
+    UserNotFound,
+    VersionNotFound,
+    MethodNotAllowed { allow: String },
+    Conflict { field: &'static str },
+    PatchConflict(String),
+    PreconditionFailed,
+    RequestTimeout,
+    PayloadTooLarge,
+    BatchTooLarge { max: usize },
+    UnsupportedMediaType { expected: String },
+    InvalidPayload(String),
+    Validation(Vec<FieldError>),
+    /// An operation that was not applied because another in its atomic batch failed.
+    BatchAborted,
+    PreconditionRequired,
+    RequestHeaderFieldsTooLarge,
+    Internal,
+    NotImplemented(String),
+    DatabaseUnavailable,
+    ServerBusy,
+}
+
+impl AppError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AppError::BadRequest(_)
+            | AppError::MalformedJson(_)
+            | AppError::InvalidPatch(_)
+            | AppError::InvalidPathParameter(_)
+            | AppError::InvalidQueryParameter { .. } => 400,
+            AppError::RouteNotFound | AppError::UserNotFound | AppError::VersionNotFound => 404,
+            AppError::MethodNotAllowed { .. } => 405,
+            AppError::Conflict { .. } | AppError::PatchConflict(_) => 409,
+            AppError::RequestTimeout => 408,
+            AppError::PreconditionFailed => 412,
+            AppError::PayloadTooLarge | AppError::BatchTooLarge { .. } => 413,
+            AppError::UnsupportedMediaType { .. } => 415,
+            AppError::InvalidPayload(_) | AppError::Validation(_) => 422,
+            AppError::BatchAborted => 424,
+            AppError::PreconditionRequired => 428,
+            AppError::RequestHeaderFieldsTooLarge => 431,
+            AppError::Internal => 500,
+            AppError::NotImplemented(_) => 501,
+            AppError::DatabaseUnavailable | AppError::ServerBusy => 503,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::MalformedJson(_) => "malformed_json",
+            AppError::InvalidPatch(_) => "invalid_patch",
+            AppError::InvalidPathParameter(_) => "invalid_path_parameter",
+            AppError::InvalidQueryParameter { .. } => "invalid_query_parameter",
+            AppError::RouteNotFound => "route_not_found",
+            AppError::UserNotFound => "user_not_found",
+            AppError::VersionNotFound => "version_not_found",
+            AppError::MethodNotAllowed { .. } => "method_not_allowed",
+            AppError::Conflict { .. } => "conflict",
+            AppError::PatchConflict(_) => "patch_conflict",
+            AppError::PreconditionFailed => "precondition_failed",
+            AppError::RequestTimeout => "request_timeout",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::BatchTooLarge { .. } => "batch_too_large",
+            AppError::UnsupportedMediaType { .. } => "unsupported_media_type",
+            AppError::InvalidPayload(_) => "invalid_payload",
+            AppError::Validation(_) => "validation_failed",
+            AppError::BatchAborted => "batch_aborted",
+            AppError::PreconditionRequired => "precondition_required",
+            AppError::RequestHeaderFieldsTooLarge => "request_header_fields_too_large",
+            AppError::Internal => "internal_error",
+            AppError::NotImplemented(_) => "not_implemented",
+            AppError::DatabaseUnavailable => "database_unavailable",
+            AppError::ServerBusy => "server_busy",
+        }
+    }
+
+    /// Errors raised before a request could be fully read leave the connection
+    /// in an unknown state, so the server closes it after responding.
+    pub fn closes_connection(&self) -> bool {
+        matches!(
+            self,
+            AppError::BadRequest(_)
+                | AppError::RequestTimeout
+                | AppError::PayloadTooLarge
+                | AppError::RequestHeaderFieldsTooLarge
+                | AppError::NotImplemented(_)
+                | AppError::ServerBusy
+        )
+    }
+
+    pub fn to_response(&self) -> Response {
+        let mut response =
+            Response::new(self.status()).with_body(PROBLEM_JSON_CONTENT_TYPE, self.to_problem().to_string());
+        match self {
+            AppError::MethodNotAllowed { allow } => response = response.header("Allow", allow.as_str()),
+            AppError::ServerBusy => response = response.header("Retry-After", "1"),
+            _ => {}
+        }
+        if self.closes_connection() {
+            response = response.header("Connection", "close");
+        }
+        response
+    }
+
+    /// The RFC 7807 problem document describing this error.
+    pub fn to_problem(&self) -> Value {
+        let status = self.status();
+        let mut body = json!({
+            "type": "about:blank",
+            "title": reason_phrase(status),
+            "status": status,
+            "detail": self.to_string(),
+            "code": self.code(),
+        });
+        match self {
+            AppError::Validation(errors) => body["errors"] = json!(errors),
+            AppError::Conflict { field } => body["field"] = json!(field),
+            AppError::InvalidQueryParameter { name, .. } => body["parameter"] = json!(name),
+            _ => {}
+        }
+        body
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(detail) => write!(f, "{}", detail),
+            AppError::MalformedJson(detail) => write!(f, "request body is not valid JSON: {}", detail),
+            AppError::InvalidPatch(detail) => write!(f, "invalid patch document: {}", detail),
+            AppError::InvalidPathParameter(e) => write!(f, "{}", e),
+            AppError::InvalidQueryParameter { name, detail } => {
+                write!(f, "query parameter `{}`: {}", name, detail)
+            }
+            AppError::RouteNotFound => write!(f, "no route matches the requested path"),
+            AppError::UserNotFound => write!(f, "user not found"),
+            AppError::VersionNotFound => write!(f, "the user has no such version"),
+            AppError::MethodNotAllowed { allow } => {
+                write!(f, "method not allowed on this path, allowed: {}", allow)
+            }
+            AppError::Conflict { field } => write!(f, "a user with this {} already exists", field),
+            AppError::PatchConflict(detail) => write!(f, "patch cannot be applied: {}", detail),
+            AppError::PreconditionFailed => {
+                write!(f, "the user was modified since the given ETag was issued")
+            }
+            AppError::RequestTimeout => write!(f, "the request was not received in time"),
+            AppError::PayloadTooLarge => write!(f, "request body too large"),
+            AppError::BatchTooLarge { max } => write!(f, "a batch may have at most {} operations", max),
+            AppError::UnsupportedMediaType { expected } => {
+                write!(f, "unsupported Content-Type, expected one of: {}", expected)
+            }
+            AppError::InvalidPayload(detail) => write!(f, "request body is invalid: {}", detail),
+            AppError::Validation(errors) => write!(f, "{} field(s) failed validation", errors.len()),
+            AppError::BatchAborted => {
+                write!(f, "not applied because another operation in the atomic batch failed")
+            }
+            AppError::PreconditionRequired => {
+                write!(f, "this request must send If-Match with the user's current ETag")
+            }
+            AppError::RequestHeaderFieldsTooLarge => write!(f, "request headers too large"),
+            AppError::Internal => write!(f, "internal server error"),
+            AppError::NotImplemented(detail) => write!(f, "{}", detail),
+            AppError::DatabaseUnavailable => write!(f, "the database is currently unavailable"),
+            AppError::ServerBusy => write!(f, "server busy, retry later"),
+        }
+    }
+}
+
+impl From<ParamError> for AppError {
+    fn from(e: ParamError) -> Self {
+        AppError::InvalidPathParameter(e)
+    }
+}
+
+impl From<PatchError> for AppError {
+    fn from(e: PatchError) -> Self {
+        match e {
+            PatchError::Invalid(detail) => AppError::InvalidPatch(detail),
+            PatchError::Failed(detail) => AppError::PatchConflict(detail),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            serde_json::error::Category::Data => AppError::InvalidPayload(e.to_string()),
+            _ => AppError::MalformedJson(e.to_string()),
+        }
+    }
+}
+
+impl From<RepositoryError> for AppError {
+    fn from(e: RepositoryError) -> Self {
+        match e {
+            RepositoryError::Pool(_) => {
+                println!("Error: {}", e);
+                AppError::DatabaseUnavailable
+            }
+            RepositoryError::Conflict { field } => AppError::Conflict { field },
+            RepositoryError::VersionMismatch => AppError::PreconditionFailed,
+            // No SQLSTATE means the failure happened talking to the server, not in it.
+            RepositoryError::Database(ref db) if db.is_closed() || db.code().is_none() => {
+                println!("Error: {}", e);
+                AppError::DatabaseUnavailable
+            }
+            RepositoryError::Database(_) => {
+                println!("Error: {}", e);
+                AppError::Internal
+            }
+        }
+    }
+}
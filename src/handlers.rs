@@ -1,115 +1,374 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
-use postgres::{Client, NoTls};
-use crate::models::User;
-use crate::utils::{get_id, get_user_request_body};
-use crate::constants::{OK_RESPONSE, NOT_FOUND, INTERNAL_SERVER_ERROR};
-
-pub fn handle_client(mut stream: TcpStream, db_url: &str) {
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
- 
-    match stream.read(&mut buffer) { 
-        Ok(size) => { 
-            request.push_str(String::from_utf8_lossy(&buffer[..size]).as_ref());
-
-            let (status_line, content) = match &*request {
-                r if r.starts_with("POST /users") => handle_post_request(r, db_url),
-                r if r.starts_with("GET /users/") => handle_get_request(r, db_url),
-                r if r.starts_with("GET /users") => handle_get_all_request(r, db_url),
-                r if r.starts_with("PUT /users") => handle_put_request(r, db_url),
-                r if r.starts_with("DELETE /users/") => handle_delete_request(r, db_url),
-                _ => (NOT_FOUND.to_string(), "404 Not Found".to_string()),
-            };
-
-            stream.write_all(format!("{}{}", status_line, content).as_bytes()).unwrap();
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::audit::{parse_timestamp, AuditContext, AuditQuery};
+use crate::bulk::parse_operations;
+use crate::conditional::{body_etag, http_date, not_modified, version_etag, IfMatch};
+use crate::error::AppError;
+use crate::health::readiness;
+use crate::list_query::{parse_flag, ListQuery, SearchQuery};
+use crate::models::{NewUser, User};
+use crate::patch::{json_patch, merge_patch, PatchError, JSON_PATCH_CONTENT_TYPE, MERGE_PATCH_CONTENT_TYPE};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{HandlerResult, Params, Router};
+use crate::state::AppState;
+use crate::validation::{parse_new_user, validate_user_object, FieldError};
+
+/// Adds the user API's routes to `router`.
+pub fn user_routes(router: Router<AppState>) -> Router<AppState> {
+    router
+        .route("POST", "/users", handle_post_request)
+        .route("GET", "/users", handle_get_all_request)
+        .route("POST", "/users/bulk", handle_bulk_request)
+        .route("GET", "/users/search", handle_search_request)
+        .route("GET", "/users/{id}", handle_get_request)
+        .route("PUT", "/users/{id}", handle_put_request)
+        .route("PATCH", "/users/{id}", handle_patch_request)
+        .route("DELETE", "/users/{id}", handle_delete_request)
+        .route("POST", "/users/{id}/restore", handle_restore_request)
+        .route("POST", "/users/{id}/revert", handle_revert_request)
+        .route("GET", "/users/{id}/history", handle_history_request)
+        .route("GET", "/audit", handle_audit_request)
+        .route("GET", "/metrics/pool", handle_pool_metrics_request)
+        .route("GET", "/healthz", handle_liveness_request)
+        .route("GET", "/readyz", handle_readiness_request)
+}
+
+pub fn handle_post_request(request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let user = parse_new_user(&request.body)?;
+    state.users.create(&user, &AuditContext::from_request(request))?;
+    Ok(Response::text(200, "user created"))
+}
+
+/// Runs a batch of creates, updates and deletes and reports on each. The
+/// response is 207 unless every operation succeeded.
+pub fn handle_bulk_request(request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let atomic = match query_value(request, "atomic") {
+        Some(value) => parse_flag(&value).ok_or_else(|| AppError::InvalidQueryParameter {
+            name: "atomic".to_string(),
+            detail: "must be true or false".to_string(),
+        })?,
+        None => false,
+    };
+    let parsed = parse_operations(request, state.max_bulk_operations)?;
+
+    // `None` marks an operation that has not run (yet).
+    let mut outcomes: Vec<Option<Result<User, AppError>>> = Vec::with_capacity(parsed.len());
+    let mut names = Vec::with_capacity(parsed.len());
+    let mut operations = Vec::new();
+    let mut positions = Vec::new();
+    for (index, item) in parsed.into_iter().enumerate() {
+        match item {
+            // Like `write_precondition`, strict mode insists on a version for each write.
+            Ok(operation) if state.require_if_match && operation.is_unconditional() => {
+                names.push(Some(operation.name()));
+                outcomes.push(Some(Err(AppError::PreconditionRequired)));
+            }
+            Ok(operation) => {
+                names.push(Some(operation.name()));
+                outcomes.push(None);
+                operations.push(operation);
+                positions.push(index);
+            }
+            Err(e) => {
+                names.push(None);
+                outcomes.push(Some(Err(e)));
+            }
         }
-        Err(e) => {
-            println!("Error: {}", e);
+    }
+    // An atomic batch with an invalid operation would fail anyway.
+    if !atomic || operations.len() == outcomes.len() {
+        let results = state
+            .users
+            .bulk(&operations, atomic, &AuditContext::from_request(request))?;
+        for (position, result) in positions.into_iter().zip(results) {
+            let outcome = result
+                .map_err(AppError::from)
+                .and_then(|user| user.ok_or(AppError::UserNotFound));
+            outcomes[position] = Some(outcome);
         }
     }
+
+    let failed = outcomes.iter().any(|outcome| !matches!(outcome, Some(Ok(_))));
+    let mut succeeded = 0;
+    let results: Vec<Value> = outcomes
+        .into_iter()
+        .zip(names)
+        .enumerate()
+        .map(|(index, (outcome, op))| {
+            let mut item = json!({ "index": index });
+            if let Some(op) = op {
+                item["op"] = json!(op);
+            }
+            match outcome {
+                Some(Ok(user)) if !(atomic && failed) => {
+                    succeeded += 1;
+                    item["status"] = json!(200);
+                    item["user"] = json!(user);
+                }
+                Some(Err(e)) => {
+                    item["status"] = json!(e.status());
+                    item["error"] = e.to_problem();
+                }
+                Some(Ok(_)) | None => {
+                    item["status"] = json!(AppError::BatchAborted.status());
+                    item["error"] = AppError::BatchAborted.to_problem();
+                }
+            }
+            item
+        })
+        .collect();
+    let report = json!({
+        "atomic": atomic,
+        "succeeded": succeeded,
+        "failed": results.len() - succeeded,
+        "results": results,
+    });
+    Ok(Response::json(if failed { 207 } else { 200 }, to_json(&report)?))
 }
 
-pub fn handle_post_request(request: &str, db_url: &str) -> (String, String) {
-    match (get_user_request_body(&request), Client::connect(db_url, NoTls)) {
-        (Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "INSERT INTO users (name, email) VALUES ($1, $2)",
-                    &[&user.name, &user.email],
-                )
-                .unwrap();
-            (OK_RESPONSE.to_string(), "user created".to_string())
+pub fn handle_get_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let include_deleted = include_deleted(request)?;
+    let user = match query_value(request, "as_of") {
+        Some(value) => {
+            let as_of = parse_timestamp(&value).ok_or_else(|| AppError::InvalidQueryParameter {
+                name: "as_of".to_string(),
+                detail: "must be an RFC 3339 timestamp".to_string(),
+            })?;
+            state
+                .users
+                .get_as_of(id, as_of)?
+                .filter(|user| include_deleted || user.deleted_at.is_none())
         }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Error ".to_string(),
-        ),
+        None => state.users.get(id, include_deleted)?,
+    };
+    let user = user.ok_or(AppError::UserNotFound)?;
+    cacheable_json_response(request, &user, Some(version_etag(user.version)), Some(user.updated_at), Vec::new())
+}
+
+pub fn handle_get_all_request(request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let query = ListQuery::from_request(request)?;
+    let page = state.users.list(&query)?;
+
+    let mut headers = Vec::new();
+    if let Some(links) = query.links(&page) {
+        headers.push(("Link", links));
+    }
+    if let Some(total) = page.total {
+        headers.push(("X-Total-Count", total.to_string()));
     }
+    // No Last-Modified: the newest `updated_at` on a page does not advance when
+    // a user is deleted, so only the body ETag is a sound validator here.
+    cacheable_json_response(request, &page.users, None, None, headers)
 }
 
-pub fn handle_get_request(request: &str, db_url: &str) -> (String, String) {
-    match (get_id(request).parse::<i32>(), Client::connect(db_url, NoTls)){
-        (Ok(id), Ok(mut client)) => 
-        match client.query_one("SELECT * FROM users WHERE id = $1", &[&id]) {
-            Ok(row) => {
-                let user = User {
-                    id: row.get(0),
-                    name: row.get(1),
-                    email: row.get(2),
-                };
-                (OK_RESPONSE.to_string(), serde_json::to_string(&user).unwrap())
-            }
-            _ => (NOT_FOUND.to_string(), "User not found".to_string()),
+pub fn handle_search_request(request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let query = SearchQuery::from_request(request)?;
+    let hits = state.users.search(&query.text, query.limit)?;
+    json_response(&hits)
+}
+
+pub fn handle_put_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let if_match = write_precondition(request, state)?;
+    let user = parse_new_user(&request.body)?;
+    let updated = state
+        .users
+        .update(id, &user, if_match.versions(), &AuditContext::from_request(request))?
+        .ok_or(AppError::UserNotFound)?;
+    Ok(Response::text(200, "User updated").header("ETag", version_etag(updated.version)))
+}
+
+pub fn handle_patch_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let if_match = write_precondition(request, state)?;
+    let content_type = request
+        .header("Content-Type")
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let apply: fn(&mut Value, &Value) -> Result<(), PatchError> = match content_type.as_deref() {
+        Some(MERGE_PATCH_CONTENT_TYPE) => |document, patch| {
+            merge_patch(document, patch);
+            Ok(())
+        },
+        Some(JSON_PATCH_CONTENT_TYPE) => json_patch,
+        _ => {
+            return Err(AppError::UnsupportedMediaType {
+                expected: format!("{}, {}", MERGE_PATCH_CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE),
+            })
         }
-        _ => (INTERNAL_SERVER_ERROR.to_string(), "Error".to_string()),
+    };
+    let patch: Value = serde_json::from_slice(&request.body)?;
+
+    let user = state.users.get(id, false)?.ok_or(AppError::UserNotFound)?;
+    if if_match.versions().is_some_and(|versions| !versions.contains(&user.version)) {
+        return Err(AppError::PreconditionFailed);
     }
+    // The id is not part of the patchable document, so patches cannot touch it.
+    let mut document = json!({ "name": user.name, "email": user.email });
+    apply(&mut document, &patch)?;
+    let Value::Object(object) = document else {
+        return Err(AppError::Validation(vec![FieldError::new("", "patched document must be a JSON object")]));
+    };
+    let changes = validate_user_object(&object)?;
+
+    // The patch was applied to this version, so a write in between must not be
+    // overwritten; it fails with 412 even if the client sent no If-Match.
+    let updated = state
+        .users
+        .update(id, &changes, Some(&[user.version]), &AuditContext::from_request(request))?
+        .ok_or(AppError::UserNotFound)?;
+    json_response_with_headers(&updated, &[("ETag", version_etag(updated.version))])
 }
 
-pub fn handle_get_all_request(_request: &str, db_url: &str) -> (String, String) {
-    match Client::connect(db_url, NoTls) {
-        Ok(mut client) => {
-            let mut users = Vec::new();
-            for row in client.query("SELECT * FROM users", &[]).unwrap() {
-                users.push(User {
-                    id: row.get(0),
-                    name: row.get(1),
-                    email: row.get(2),
-                });
-            }
-            (OK_RESPONSE.to_string(), serde_json::to_string(&users).unwrap())
-        }
-        _ => (INTERNAL_SERVER_ERROR.to_string(), "Error".to_string()),
+pub fn handle_delete_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let if_match = write_precondition(request, state)?;
+    if !state
+        .users
+        .delete(id, if_match.versions(), &AuditContext::from_request(request))? {
+        return Err(AppError::UserNotFound);
     }
-} 
-
-pub fn handle_put_request(request: &str, db_url: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(),
-    get_user_request_body(&request),
-    Client::connect(db_url, NoTls)) { 
-        (Ok(id), Ok(user), Ok(mut client)) => {
-            client.execute(
-                "UPDATE users SET name = $1, email = $2 WHERE id = $3",
-                &[&user.name, &user.email, &id]
-            ).unwrap();
-            (OK_RESPONSE.to_string(), "User updated".to_string())
+    Ok(Response::text(200, "User deleted"))
+}
+
+pub fn handle_restore_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let if_match = write_precondition(request, state)?;
+    let user = state
+        .users
+        .restore(id, if_match.versions(), &AuditContext::from_request(request))?
+        .ok_or(AppError::UserNotFound)?;
+    json_response_with_headers(&user, &[("ETag", version_etag(user.version))])
+}
+
+/// Makes a past version's name and email current again, as a new version.
+/// Deletion is left alone; that is what restore is for.
+pub fn handle_revert_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let if_match = write_precondition(request, state)?;
+    let version = match query_value(request, "version").map(|value| value.parse::<i32>()) {
+        Some(Ok(version)) if version >= 1 => version,
+        _ => {
+            return Err(AppError::InvalidQueryParameter {
+                name: "version".to_string(),
+                detail: "must be a positive integer".to_string(),
+            })
         }
-        _ => (INTERNAL_SERVER_ERROR.to_string(), "Error".to_string()),
+    };
+
+    let user = state.users.get(id, false)?.ok_or(AppError::UserNotFound)?;
+    if if_match.versions().is_some_and(|versions| !versions.contains(&user.version)) {
+        return Err(AppError::PreconditionFailed);
     }
+    let target = state.users.get_version(id, version)?.ok_or(AppError::VersionNotFound)?;
+    let reverted = NewUser {
+        name: target.name,
+        email: target.email,
+    };
+    let updated = state
+        .users
+        .update(id, &reverted, Some(&[user.version]), &AuditContext::from_request(request))?
+        .ok_or(AppError::UserNotFound)?;
+    json_response_with_headers(&updated, &[("ETag", version_etag(updated.version))])
 }
 
-pub fn handle_delete_request(request: &str, db_url: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(db_url, NoTls)) {
-        (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute(
-                "DELETE FROM users WHERE id = $1",
-                &[&id]
-            ).unwrap();
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "User not found".to_string());
-            }
-            (OK_RESPONSE.to_string(), "User deleted".to_string())
-        }
-        _ => (INTERNAL_SERVER_ERROR.to_string(), "Error".to_string()),
+/// A user's audit entries; these outlive the user, so a purged user still has a history.
+pub fn handle_history_request(request: &Request, params: &Params, state: &AppState) -> HandlerResult {
+    let id = params.get::<i32>("id")?;
+    let query = AuditQuery::from_request(request)?;
+    let entries = state.users.audit_entries(Some(id), &query)?;
+    if entries.is_empty() && state.users.get(id, true)?.is_none() {
+        return Err(AppError::UserNotFound);
+    }
+    json_response(&entries)
+}
+
+pub fn handle_audit_request(request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let query = AuditQuery::from_request(request)?;
+    json_response(&state.users.audit_entries(None, &query)?)
+}
+
+pub fn handle_pool_metrics_request(_request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let pool = state.pool.as_ref().ok_or(AppError::RouteNotFound)?;
+    json_response(&pool.status())
+}
+
+/// Answers as long as the process can serve requests at all.
+pub fn handle_liveness_request(_request: &Request, _params: &Params, _state: &AppState) -> HandlerResult {
+    Ok(Response::json(200, json!({ "status": "ok" }).to_string()).header("Cache-Control", "no-store"))
+}
+
+/// 200 when the server should get traffic, 503 with the failing checks otherwise.
+pub fn handle_readiness_request(_request: &Request, _params: &Params, state: &AppState) -> HandlerResult {
+    let readiness = readiness(state);
+    let status = if readiness.is_ready() { 200 } else { 503 };
+    Ok(Response::json(status, to_json(&readiness)?).header("Cache-Control", "no-store"))
+}
+
+fn query_value(request: &Request, name: &str) -> Option<String> {
+    request
+        .query_pairs()
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+fn include_deleted(request: &Request) -> Result<bool, AppError> {
+    match request.query_pairs().into_iter().find(|(name, _)| name == "include_deleted") {
+        Some((name, value)) => parse_flag(&value).ok_or_else(|| AppError::InvalidQueryParameter {
+            name,
+            detail: "must be true or false".to_string(),
+        }),
+        None => Ok(false),
+    }
+}
+
+/// Reads `If-Match`, insisting on it when the server requires conditional writes.
+fn write_precondition(request: &Request, state: &AppState) -> Result<IfMatch, AppError> {
+    let if_match = IfMatch::from_request(request);
+    if state.require_if_match && matches!(if_match, IfMatch::Absent) {
+        return Err(AppError::PreconditionRequired);
     }
-}
\ No newline at end of file
+    Ok(if_match)
+}
+
+fn json_response<T: Serialize>(value: &T) -> HandlerResult {
+    json_response_with_headers(value, &[])
+}
+
+/// Like `json_response_with_headers`, but carries `ETag` and `Last-Modified`
+/// validators and answers 304 without a body when the client's copy is current.
+/// Without an explicit `etag` one is derived from the body.
+fn cacheable_json_response<T: Serialize>(
+    request: &Request,
+    value: &T,
+    etag: Option<String>,
+    last_modified: Option<DateTime<Utc>>,
+    headers: Vec<(&str, String)>,
+) -> HandlerResult {
+    let body = to_json(value)?;
+    let etag = etag.unwrap_or_else(|| body_etag(&body));
+    let mut validators = vec![("ETag", etag.clone())];
+    if let Some(last_modified) = &last_modified {
+        validators.push(("Last-Modified", http_date(last_modified)));
+    }
+    if not_modified(request, &etag, last_modified.as_ref()) {
+        return Ok(Response::new(304).headers(validators));
+    }
+    validators.extend(headers);
+    Ok(Response::json(200, body).headers(validators))
+}
+
+fn json_response_with_headers<T: Serialize>(value: &T, headers: &[(&str, String)]) -> HandlerResult {
+    Ok(Response::json(200, to_json(value)?).headers(headers.iter().cloned()))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, AppError> {
+    serde_json::to_string(value).map_err(|e| {
+        println!("Error serializing response: {}", e);
+        AppError::Internal
+    })
+}
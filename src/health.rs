@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_derive::Serialize;
+use crate::connection_pool::ConnectionPool;
+use crate::database::outstanding_migrations;
+use crate::state::AppState;
+
+/// How long `/readyz` waits for the database before reporting it down.
+pub const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The body of `GET /readyz`: ready only if every check is up.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn up() -> Check {
+        Check {
+            status: "up",
+            latency_ms: None,
+            detail: None,
+        }
+    }
+
+    fn down(detail: impl Into<String>) -> Check {
+        Check {
+            status: "down",
+            latency_ms: None,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == "ready"
+    }
+}
+
+/// Whether the server should be sent traffic: not shutting down and, with
+/// Postgres, the database answers and has every migration this binary expects.
+pub fn readiness(state: &AppState) -> Readiness {
+    let mut checks = BTreeMap::new();
+    let shutdown = if state.draining.load(Ordering::SeqCst) {
+        Check::down("draining")
+    } else {
+        Check::up()
+    };
+    checks.insert("shutdown", shutdown);
+    if let Some(pool) = &state.pool {
+        let (database, migrations) = check_database(Arc::clone(pool));
+        checks.insert("database", database);
+        checks.insert("migrations", migrations);
+    }
+    let ready = checks.values().all(|check| check.status == "up");
+    Readiness {
+        status: if ready { "ready" } else { "not_ready" },
+        checks,
+    }
+}
+
+/// Probes on a thread of its own, so a hung connection costs the probe no
+/// more than the timeout.
+fn check_database(pool: Arc<ConnectionPool>) -> (Check, Check) {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(probe(&pool));
+    });
+    match receiver.recv_timeout(READINESS_TIMEOUT) {
+        Ok(Ok((latency, migrations))) => {
+            let database = Check {
+                latency_ms: Some(latency.as_millis()),
+                ..Check::up()
+            };
+            (database, migrations)
+        }
+        Ok(Err(e)) => (Check::down(e), Check::down("database unreachable")),
+        Err(_) => {
+            let detail = format!("no answer within {}ms", READINESS_TIMEOUT.as_millis());
+            (Check::down(detail), Check::down("database unreachable"))
+        }
+    }
+}
+
+/// `SELECT 1`, then the migrations; fails only if the database is unreachable.
+fn probe(pool: &ConnectionPool) -> Result<(Duration, Check), String> {
+    let started = Instant::now();
+    let mut client = pool.get().map_err(|e| e.to_string())?;
+    client.query_one("SELECT 1", &[]).map_err(|e| e.to_string())?;
+    let latency = started.elapsed();
+    let migrations = match outstanding_migrations(&mut client) {
+        Ok(outstanding) if outstanding.is_empty() => Check::up(),
+        Ok(outstanding) => {
+            let names: Vec<&str> = outstanding.iter().map(|migration| migration.name).collect();
+            Check::down(format!("not applied: {}", names.join(", ")))
+        }
+        Err(e) => Check::down(e.to_string()),
+    };
+    Ok((latency, migrations))
+}
@@ -0,0 +1,42 @@
+//! A user CRUD API over HTTP/1.1, backed by Postgres or memory.
+//!
+//! The `rust_crud_api` binary is a thin CLI over this crate. To mount the API
+//! in your own binary, or to test against a real listener, configure a
+//! [`Server`] with [`Server::builder`], `build` it and `run` it.
+
+pub mod audit;
+pub mod bulk;
+pub mod config;
+pub mod connection_pool;
+pub mod database;
+pub mod error;
+pub mod health;
+pub mod list_query;
+pub mod logging;
+pub mod models;
+pub mod purge;
+pub mod repository;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod state;
+
+mod conditional;
+mod constants;
+mod handlers;
+mod patch;
+mod thread_pool;
+mod utils;
+mod validation;
+
+pub use crate::config::{Config, ConfigError};
+pub use crate::connection_pool::{ConnectionPool, PoolConfig};
+pub use crate::error::AppError;
+pub use crate::models::{NewUser, User};
+pub use crate::repository::{InMemoryUserRepository, PostgresUserRepository, RepositoryError, UserRepository};
+pub use crate::request::{Request, RequestLimits};
+pub use crate::response::Response;
+pub use crate::router::{Handler, HandlerResult, Params};
+pub use crate::server::{Middleware, Next, Server, ServerBuilder, Shutdown, ShutdownHandle};
+pub use crate::state::AppState;
@@ -0,0 +1,666 @@
+use std::cmp::Ordering;
+use serde_json::{json, Value};
+use crate::error::AppError;
+use crate::models::User;
+use crate::request::Request;
+use crate::utils::percent_encode;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+pub const DEFAULT_SEARCH_LIMIT: i64 = 20;
+pub const MAX_SEARCH_LIMIT: i64 = 100;
+
+const CURSOR_PREFIX: &str = "u2:";
+
+/// Columns a list can be sorted by. Only these ever reach SQL, as fixed strings.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortField {
+    Id,
+    Name,
+    Email,
+}
+
+impl SortField {
+    fn parse(name: &str) -> Option<SortField> {
+        match name {
+            "id" => Some(SortField::Id),
+            "name" => Some(SortField::Name),
+            "email" => Some(SortField::Email),
+            _ => None,
+        }
+    }
+
+    pub fn column(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::Email => "email",
+        }
+    }
+
+    /// The column as SQL compares and orders it. Text uses the `"C"` collation,
+    /// i.e. code point order, so Postgres sorts exactly as `SortValue` does
+    /// whatever the database's default collation is.
+    pub fn sort_expression(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name COLLATE \"C\"",
+            SortField::Email => "email COLLATE \"C\"",
+        }
+    }
+
+    pub fn value_of(&self, user: &User) -> SortValue {
+        match self {
+            SortField::Id => SortValue::Int(user.id.unwrap_or_default()),
+            SortField::Name => SortValue::Text(user.name.clone()),
+            SortField::Email => SortValue::Text(user.email.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+#[derive(Clone, PartialEq, PartialOrd)]
+pub enum SortValue {
+    Int(i32),
+    Text(String),
+}
+
+/// A whitelisted filter. `*Like` filters take SQL `LIKE` patterns (`%`, `_`)
+/// and, like email equality, ignore case.
+#[derive(Clone)]
+pub enum Filter {
+    NameEquals(String),
+    NameLike(String),
+    EmailEquals(String),
+    EmailLike(String),
+}
+
+impl Filter {
+    fn parse(name: &str, value: String) -> Option<Filter> {
+        match name {
+            "name" => Some(Filter::NameEquals(value)),
+            "name_like" => Some(Filter::NameLike(value)),
+            "email" => Some(Filter::EmailEquals(value)),
+            "email_like" => Some(Filter::EmailLike(value)),
+            _ => None,
+        }
+    }
+
+    fn parameter(&self) -> (&'static str, &str) {
+        match self {
+            Filter::NameEquals(value) => ("name", value),
+            Filter::NameLike(value) => ("name_like", value),
+            Filter::EmailEquals(value) => ("email", value),
+            Filter::EmailLike(value) => ("email_like", value),
+        }
+    }
+
+    pub fn matches(&self, user: &User) -> bool {
+        match self {
+            Filter::NameEquals(value) => user.name == *value,
+            Filter::NameLike(pattern) => like_matches(pattern, &user.name),
+            Filter::EmailEquals(value) => user.email.to_lowercase() == value.to_lowercase(),
+            Filter::EmailLike(pattern) => like_matches(pattern, &user.email),
+        }
+    }
+}
+
+/// Where in the sorted list a page starts.
+#[derive(Clone, PartialEq)]
+pub enum Position {
+    Start,
+    Offset(i64),
+    /// Rows sorting after the row with these sort key values.
+    After(Vec<SortValue>),
+    /// Rows sorting before the row with these sort key values, still returned
+    /// in sort order.
+    Before(Vec<SortValue>),
+}
+
+/// The parsed query string of `GET /users/search`.
+pub struct SearchQuery {
+    pub text: String,
+    pub limit: i64,
+}
+
+impl SearchQuery {
+    pub fn from_request(request: &Request) -> Result<SearchQuery, AppError> {
+        let mut text = None;
+        let mut limit = DEFAULT_SEARCH_LIMIT;
+        for (name, value) in request.query_pairs() {
+            let invalid = |detail: &str| AppError::InvalidQueryParameter {
+                name: name.clone(),
+                detail: detail.to_string(),
+            };
+            match name.as_str() {
+                "q" => text = Some(value.trim().to_string()),
+                "limit" => match value.parse::<i64>() {
+                    Ok(value) if value >= 1 => limit = value.min(MAX_SEARCH_LIMIT),
+                    _ => return Err(invalid("must be a positive integer")),
+                },
+                _ => return Err(invalid("unknown query parameter")),
+            }
+        }
+        match text {
+            Some(text) if !text.is_empty() => Ok(SearchQuery { text, limit }),
+            _ => Err(AppError::InvalidQueryParameter {
+                name: "q".to_string(),
+                detail: "is required and must not be blank".to_string(),
+            }),
+        }
+    }
+}
+
+/// The parsed query string of `GET /users`.
+pub struct ListQuery {
+    pub limit: i64,
+    pub position: Position,
+    pub include_total: bool,
+    /// Also list soft-deleted users.
+    pub include_deleted: bool,
+    pub filters: Vec<Filter>,
+    /// Always ends with `id` so that the order, and thus every cursor, is total.
+    pub sort: Vec<SortKey>,
+}
+
+pub struct Page {
+    pub users: Vec<User>,
+    /// Whether more rows exist past this page in the direction of travel,
+    /// i.e. after it for `Start`/`Offset`/`After` and before it for `Before`.
+    pub has_more: bool,
+    pub total: Option<i64>,
+}
+
+impl ListQuery {
+    pub fn from_request(request: &Request) -> Result<ListQuery, AppError> {
+        let mut query = ListQuery {
+            limit: DEFAULT_PAGE_SIZE,
+            position: Position::Start,
+            include_total: false,
+            include_deleted: false,
+            filters: Vec::new(),
+            sort: Vec::new(),
+        };
+        let mut cursor = None;
+
+        for (name, value) in request.query_pairs() {
+            let invalid = |detail: &str| AppError::InvalidQueryParameter {
+                name: name.clone(),
+                detail: detail.to_string(),
+            };
+            match name.as_str() {
+                "limit" => {
+                    let limit: i64 = value.parse().map_err(|_| invalid("must be a positive integer"))?;
+                    if limit < 1 {
+                        return Err(invalid("must be a positive integer"));
+                    }
+                    // Larger pages are clamped rather than rejected.
+                    query.limit = limit.min(MAX_PAGE_SIZE);
+                }
+                "offset" | "after" | "before" => {
+                    if query.position != Position::Start || cursor.is_some() {
+                        return Err(invalid("only one of offset, after and before may be given"));
+                    }
+                    if name == "offset" {
+                        query.position = match value.parse::<i64>() {
+                            Ok(offset) if offset >= 0 => Position::Offset(offset),
+                            _ => return Err(invalid("must be a non-negative integer")),
+                        };
+                    } else {
+                        // A cursor can only be checked against the sort once every parameter is read.
+                        cursor = Some((name.clone(), value));
+                    }
+                }
+                "include_total" => {
+                    query.include_total = parse_flag(&value).ok_or_else(|| invalid("must be true or false"))?;
+                }
+                "include_deleted" => {
+                    query.include_deleted = parse_flag(&value).ok_or_else(|| invalid("must be true or false"))?;
+                }
+                "sort" => {
+                    for item in value.split(',') {
+                        let (descending, field) = match item.trim().strip_prefix('-') {
+                            Some(field) => (true, field),
+                            None => (false, item.trim()),
+                        };
+                        let field = SortField::parse(field).ok_or_else(|| {
+                            invalid(&format!("cannot sort by `{}`, allowed: id, name, email", field))
+                        })?;
+                        if query.sort.iter().any(|key| key.field == field) {
+                            return Err(invalid(&format!("`{}` is listed more than once", field.column())));
+                        }
+                        query.sort.push(SortKey { field, descending });
+                    }
+                }
+                _ => match Filter::parse(&name, value) {
+                    Some(filter) => query.filters.push(filter),
+                    None => return Err(invalid("unknown query parameter")),
+                },
+            }
+        }
+
+        if !query.sort.iter().any(|key| key.field == SortField::Id) {
+            query.sort.push(SortKey {
+                field: SortField::Id,
+                descending: false,
+            });
+        }
+
+        if let Some((name, value)) = cursor {
+            let values = decode_cursor(&value, &query.sort).ok_or_else(|| AppError::InvalidQueryParameter {
+                name: name.clone(),
+                detail: "invalid cursor, or a cursor taken from a differently sorted list".to_string(),
+            })?;
+            query.position = if name == "after" {
+                Position::After(values)
+            } else {
+                Position::Before(values)
+            };
+        }
+
+        Ok(query)
+    }
+
+    /// The values of this query's sort keys for `user`, i.e. its cursor.
+    pub fn sort_values(&self, user: &User) -> Vec<SortValue> {
+        self.sort.iter().map(|key| key.field.value_of(user)).collect()
+    }
+
+    /// Orders `user` relative to the row with the given sort key values.
+    pub fn compare(&self, user: &User, values: &[SortValue]) -> Ordering {
+        for (key, value) in self.sort.iter().zip(values) {
+            let ordering = key
+                .field
+                .value_of(user)
+                .partial_cmp(value)
+                .unwrap_or(Ordering::Equal);
+            let ordering = if key.descending { ordering.reverse() } else { ordering };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// Builds the `Link` header value pointing at the pages around `page`.
+    pub fn links(&self, page: &Page) -> Option<String> {
+        let mut links = Vec::new();
+        let first = page.users.first().map(|user| self.sort_values(user));
+        let last = page.users.last().map(|user| self.sort_values(user));
+
+        let (next, prev) = match self.position {
+            Position::Offset(offset) => (
+                page.has_more.then_some(Position::Offset(offset.saturating_add(self.limit))),
+                (offset > 0).then_some(Position::Offset((offset - self.limit).max(0))),
+            ),
+            Position::Start => (last.filter(|_| page.has_more).map(Position::After), None),
+            Position::After(_) => (
+                last.filter(|_| page.has_more).map(Position::After),
+                first.map(Position::Before),
+            ),
+            Position::Before(_) => (
+                last.map(Position::After),
+                first.filter(|_| page.has_more).map(Position::Before),
+            ),
+        };
+
+        if let Some(next) = next {
+            links.push(format!("</users?{}>; rel=\"next\"", self.query_string(&next)));
+        }
+        if let Some(prev) = prev {
+            links.push(format!("</users?{}>; rel=\"prev\"", self.query_string(&prev)));
+        }
+        (!links.is_empty()).then(|| links.join(", "))
+    }
+
+    fn query_string(&self, position: &Position) -> String {
+        let mut params = vec![format!("limit={}", self.limit)];
+        for filter in &self.filters {
+            let (name, value) = filter.parameter();
+            params.push(format!("{}={}", name, percent_encode(value)));
+        }
+        params.push(format!("sort={}", percent_encode(&sort_signature(&self.sort))));
+        match position {
+            Position::Start => {}
+            Position::Offset(offset) => params.push(format!("offset={}", offset)),
+            Position::After(values) => params.push(format!("after={}", encode_cursor(values, &self.sort))),
+            Position::Before(values) => params.push(format!("before={}", encode_cursor(values, &self.sort))),
+        }
+        if self.include_total {
+            params.push("include_total=true".to_string());
+        }
+        if self.include_deleted {
+            params.push("include_deleted=true".to_string());
+        }
+        params.join("&")
+    }
+}
+
+/// A boolean query parameter; a bare `?flag` means true.
+pub fn parse_flag(value: &str) -> Option<bool> {
+    match value {
+        "true" | "1" | "" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn sort_signature(sort: &[SortKey]) -> String {
+    sort.iter()
+        .map(|key| format!("{}{}", if key.descending { "-" } else { "" }, key.field.column()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Cursors are opaque to clients; the encoding only needs to round-trip and
+/// to remember which sort it was taken from.
+fn encode_cursor(values: &[SortValue], sort: &[SortKey]) -> String {
+    let values: Vec<Value> = values
+        .iter()
+        .map(|value| match value {
+            SortValue::Int(i) => json!(i),
+            SortValue::Text(s) => json!(s),
+        })
+        .collect();
+    let payload = json!({ "s": sort_signature(sort), "v": values });
+    format!("{}{}", CURSOR_PREFIX, payload)
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn decode_cursor(cursor: &str, sort: &[SortKey]) -> Option<Vec<SortValue>> {
+    // An odd trailing digit fails the `get` below, so no separate length check.
+    let bytes: Option<Vec<u8>> = (0..cursor.len())
+        .step_by(2)
+        .map(|i| cursor.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+        .collect();
+    let text = String::from_utf8(bytes?).ok()?;
+    let payload: Value = serde_json::from_str(text.strip_prefix(CURSOR_PREFIX)?).ok()?;
+    if payload.get("s")?.as_str()? != sort_signature(sort) {
+        return None;
+    }
+
+    let values = payload.get("v")?.as_array()?;
+    if values.len() != sort.len() {
+        return None;
+    }
+    sort.iter()
+        .zip(values)
+        .map(|(key, value)| match key.field {
+            SortField::Id => value.as_i64().and_then(|i| i32::try_from(i).ok()).map(SortValue::Int),
+            SortField::Name | SortField::Email => value.as_str().map(|s| SortValue::Text(s.to_string())),
+        })
+        .collect()
+}
+
+enum LikeToken {
+    AnyRun,
+    AnyChar,
+    Literal(char),
+}
+
+/// Case-insensitive SQL `LIKE`: `%` matches any run of characters, `_` exactly
+/// one, and a backslash makes the next character literal.
+pub fn like_matches(pattern: &str, text: &str) -> bool {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().flat_map(char::to_lowercase);
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '%' => LikeToken::AnyRun,
+            '_' => LikeToken::AnyChar,
+            '\\' => LikeToken::Literal(chars.next().unwrap_or('\\')),
+            c => LikeToken::Literal(c),
+        });
+    }
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+
+    // Classic wildcard matching: remember the last `%` and retry from there,
+    // which keeps the worst case at O(pattern * text).
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(LikeToken::AnyRun) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(LikeToken::AnyChar) => {
+                p += 1;
+                t += 1;
+            }
+            Some(LikeToken::Literal(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[p..].iter().all(|token| matches!(token, LikeToken::AnyRun))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn query(query_string: &str) -> Result<ListQuery, AppError> {
+        ListQuery::from_request(&Request {
+            method: "GET".to_string(),
+            version: "HTTP/1.1".to_string(),
+            path: "/users".to_string(),
+            query: Some(query_string.to_string()),
+            headers: Vec::new(),
+            body: Vec::new(),
+            id: "test".to_string(),
+        })
+    }
+
+    fn user(id: i32, name: &str, email: &str) -> User {
+        User {
+            id: Some(id),
+            name: name.to_string(),
+            email: email.to_string(),
+            version: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            deleted_at: None,
+        }
+    }
+
+    fn users() -> Vec<User> {
+        vec![
+            user(1, "Bob", "bob@example.com"),
+            user(2, "ann", "ann@example.com"),
+            user(3, "Bob", "robert@example.com"),
+            user(4, "Ann", "ann2@example.com"),
+            user(5, "bob", "b@example.com"),
+            user(6, "Bob", "bobby@example.com"),
+        ]
+    }
+
+    fn ids(users: &[&User]) -> Vec<i32> {
+        users.iter().map(|user| user.id.unwrap()).collect()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let query = query("sort=-name,email").unwrap();
+        let values = vec![
+            SortValue::Text("Zoë \"quoted\" \\ /".to_string()),
+            SortValue::Text("z@example.com".to_string()),
+            SortValue::Int(i32::MAX),
+        ];
+        let cursor = encode_cursor(&values, &query.sort);
+        assert!(cursor.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert!(decode_cursor(&cursor, &query.sort) == Some(values));
+    }
+
+    #[test]
+    fn cursor_from_another_sort_is_rejected() {
+        let by_name = query("sort=name").unwrap();
+        let cursor = encode_cursor(&by_name.sort_values(&user(1, "Ann", "a@example.com")), &by_name.sort);
+        assert!(decode_cursor(&cursor, &by_name.sort).is_some());
+        for sort in ["", "sort=-name", "sort=name,-id", "sort=email"] {
+            assert!(decode_cursor(&cursor, &query(sort).unwrap().sort).is_none(), "{}", sort);
+            let query_string = format!("{}&after={}", sort, cursor);
+            assert!(matches!(
+                query(&query_string),
+                Err(AppError::InvalidQueryParameter { name, .. }) if name == "after"
+            ));
+        }
+        assert!(query(&format!("sort=name&before={}", cursor)).is_ok());
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let sort = query("").unwrap().sort;
+        let valid = encode_cursor(&[SortValue::Int(7)], &sort);
+        for cursor in [
+            "",
+            "zz",
+            &valid[..valid.len() - 1],
+            &valid[2..],
+            &encode_cursor(&[SortValue::Int(7), SortValue::Int(8)], &sort),
+            &encode_cursor(&[SortValue::Text("7".to_string())], &sort),
+        ] {
+            assert!(decode_cursor(cursor, &sort).is_none(), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn sort_always_ends_with_id() {
+        let columns = |query: ListQuery| sort_signature(&query.sort);
+        assert_eq!(columns(query("").unwrap()), "id");
+        assert_eq!(columns(query("sort=-name").unwrap()), "-name,id");
+        assert_eq!(columns(query("sort=-id,name").unwrap()), "-id,name");
+        assert!(query("sort=name,name").is_err());
+        assert!(query("sort=password").is_err());
+    }
+
+    /// Whether `user` satisfies the predicate `PostgresUserRepository` sends for
+    /// a cursor: `(a > x) OR (a = x AND b > y) OR ...`, flipped for descending
+    /// keys and for `before`.
+    fn sql_keyset(query: &ListQuery, user: &User, values: &[SortValue], backwards: bool) -> bool {
+        (0..query.sort.len()).any(|i| {
+            let equal = query.sort[..i]
+                .iter()
+                .zip(values)
+                .all(|(key, value)| key.field.value_of(user) == *value);
+            let key = query.sort[i];
+            let actual = key.field.value_of(user);
+            let after = if key.descending != backwards { actual < values[i] } else { actual > values[i] };
+            equal && after
+        })
+    }
+
+    #[test]
+    fn keyset_comparison_agrees_with_sql_predicate() {
+        let users = users();
+        let sorts = ["", "sort=-id", "sort=name", "sort=-name", "sort=name,-email", "sort=-email,name", "sort=-name,-id"];
+        for sort in sorts {
+            let query = query(sort).unwrap();
+            for cursor in &users {
+                let values = query.sort_values(cursor);
+                for user in &users {
+                    let ordering = query.compare(user, &values);
+                    assert_eq!(sql_keyset(&query, user, &values, false), ordering == Ordering::Greater, "{}", sort);
+                    assert_eq!(sql_keyset(&query, user, &values, true), ordering == Ordering::Less, "{}", sort);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keyset_pages_cover_the_sorted_list() {
+        let users = users();
+        let query = query("sort=-name,email").unwrap();
+        let mut sorted: Vec<&User> = users.iter().collect();
+        sorted.sort_by(|a, b| query.compare(a, &query.sort_values(b)));
+        // Code point order, as with `COLLATE "C"`: upper case sorts before lower case.
+        assert_eq!(ids(&sorted), vec![5, 2, 1, 6, 3, 4]);
+
+        for (i, cursor) in sorted.iter().enumerate() {
+            let values = query.sort_values(cursor);
+            let after: Vec<&User> = sorted
+                .iter()
+                .copied()
+                .filter(|user| query.compare(user, &values) == Ordering::Greater)
+                .collect();
+            assert_eq!(ids(&after), ids(&sorted[i + 1..]));
+            let before: Vec<&User> = sorted
+                .iter()
+                .copied()
+                .filter(|user| query.compare(user, &values) == Ordering::Less)
+                .collect();
+            assert_eq!(ids(&before), ids(&sorted[..i]));
+        }
+    }
+
+    #[test]
+    fn like_wildcards() {
+        assert!(like_matches("ann%", "Anna"));
+        assert!(like_matches("%@EXAMPLE.com", "ann@example.com"));
+        assert!(like_matches("a_c", "abc"));
+        assert!(!like_matches("a_c", "ac"));
+        assert!(!like_matches("a_c", "abbc"));
+        assert!(like_matches("%", ""));
+        assert!(like_matches("", ""));
+        assert!(!like_matches("", "a"));
+        assert!(!like_matches("a%", ""));
+        assert!(like_matches("ÄB%", "äbc"));
+    }
+
+    #[test]
+    fn like_backslash_escapes() {
+        assert!(like_matches("100\\%", "100%"));
+        assert!(!like_matches("100\\%", "1000"));
+        assert!(like_matches("a\\_b", "a_b"));
+        assert!(!like_matches("a\\_b", "axb"));
+        assert!(like_matches("a\\\\b", "a\\b"));
+        assert!(like_matches("\\a%", "abc"));
+    }
+
+    #[test]
+    fn like_backtracks_over_percent() {
+        assert!(like_matches("%ab%ab", "xabyab"));
+        assert!(like_matches("%aab", "aaab"));
+        assert!(like_matches("%a%b%c", "xaybzc"));
+        assert!(!like_matches("%a%b%c", "cba"));
+        assert!(like_matches("a%%b", "ab"));
+        assert!(like_matches("%_b", "aab"));
+        assert!(!like_matches("%_b", "b"));
+        assert!(!like_matches("%ab", "abba"));
+        assert!(like_matches(&"%a".repeat(20), &"a".repeat(40)));
+        assert!(!like_matches(&format!("{}b", "%a".repeat(20)), &"a".repeat(40)));
+    }
+
+    #[test]
+    fn links_carry_the_sort_and_cursor() {
+        let query = query("limit=2&sort=-name&name_like=b%25").unwrap();
+        let users = users();
+        let page = Page {
+            users: vec![users[4].clone(), users[0].clone()],
+            has_more: true,
+            total: None,
+        };
+        let links = query.links(&page).unwrap();
+        let cursor = encode_cursor(&query.sort_values(&users[0]), &query.sort);
+        assert_eq!(
+            links,
+            format!("</users?limit=2&name_like=b%25&sort=-name%2Cid&after={}>; rel=\"next\"", cursor)
+        );
+    }
+}
@@ -1,42 +1,253 @@
-mod database;
-mod handlers;
-mod models;
-mod utils;
-mod constants;
-
-use crate::database::set_database;
-use crate::handlers::handle_client;
 use dotenv::dotenv;
+use rust_crud_api::config::StorageBackend;
+use rust_crud_api::database::{connect, migrate_down, migrate_up, migration_status, set_database, MigrationState};
+use rust_crud_api::logging::{self, LogLevel};
+use rust_crud_api::purge::purge_expired;
+use rust_crud_api::server::local_connect_address;
+use rust_crud_api::{
+    Config, ConnectionPool, InMemoryUserRepository, PostgresUserRepository, ServerBuilder, Shutdown, UserRepository,
+};
 use std::env;
-use std::net::TcpListener;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "usage: rust_crud_api [--config FILE] [--print-config] [--SETTING VALUE]... \
+[serve | purge | healthcheck [PATH] | migrate up | migrate down [STEPS] | migrate status]";
+
+/// How long `healthcheck` waits for the server.
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The parsed command line: options for the configuration, then the command.
+#[derive(Default)]
+struct CommandLine {
+    config_file: Option<String>,
+    print_config: bool,
+    settings: Vec<(String, String)>,
+    command: Vec<String>,
+}
 
 fn main() {
     dotenv().ok();
-    let database_url = match env::var("DATABASE_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            println!("Error: DATABASE_URL must be set in environment");
-            return;
+    let command_line = match parse_args(env::args().skip(1)) {
+        Ok(command_line) => command_line,
+        Err(e) => {
+            println!("Error: {}\n{}", e, USAGE);
+            process::exit(2);
         }
     };
-
-    if let Err(e) = set_database(&database_url) {
-        println!("Error setting up database: {}", e);
+    let config = match Config::load(command_line.config_file.as_deref(), &command_line.settings) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(2);
+        }
+    };
+    if command_line.print_config {
+        print!("{}", config.to_toml());
         return;
     }
+    logging::set_level(config.log_level);
 
-    let listener = TcpListener::bind(format!("0.0.0.0:8080")).unwrap();
-    println!("Server listening on port 8080");
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let db_url = database_url.clone();
-                handle_client(stream, &db_url);
+    let command: Vec<&str> = command_line.command.iter().map(String::as_str).collect();
+    match command.as_slice() {
+        [] | ["serve"] => serve(&config),
+        ["purge"] => {
+            if let Err(e) = purge(&config) {
+                println!("Error: {}", e);
+                process::exit(1);
             }
-            Err(e) => {
+        }
+        ["healthcheck"] | ["healthcheck", _] => {
+            if let Err(e) = healthcheck(&config, command.get(1).copied().unwrap_or("/readyz")) {
                 println!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        ["migrate", command @ ..] => {
+            if let Err(e) = migrate(&config, command) {
+                println!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        _ => {
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+/// Settings are `--name value` or `--name=value`; anything else is the command.
+fn parse_args(args: impl Iterator<Item = String>) -> Result<CommandLine, String> {
+    let mut command_line = CommandLine::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.strip_prefix("--") else {
+            command_line.command.push(arg);
+            continue;
+        };
+        if option == "print-config" {
+            command_line.print_config = true;
+            continue;
+        }
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next() {
+                Some(value) => (option.to_string(), value),
+                None => return Err(format!("--{} needs a value", option)),
+            },
+        };
+        match name.as_str() {
+            "config" => command_line.config_file = Some(value),
+            _ => command_line.settings.push((name, value)),
+        }
+    }
+    Ok(command_line)
+}
+
+fn serve(config: &Config) {
+    let (users, pool) = match storage(config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut builder = ServerBuilder::from_config(config).repository(users);
+    if let Some(pool) = pool {
+        builder = builder.pool(pool);
+    }
+    let server = match builder.build() {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Error binding {}: {}", config.server.bind, e);
+            process::exit(1);
+        }
+    };
+
+    // SIGINT or SIGTERM drains; a second one gives up on the in-flight requests.
+    let shutdown = server.shutdown_handle();
+    let installed = ctrlc::set_handler(move || {
+        if shutdown.is_requested() {
+            println!("Error: signalled again, exiting without draining");
+            process::exit(3);
+        }
+        shutdown.shutdown();
+    });
+    if let Err(e) = installed {
+        println!("Error installing signal handler: {}", e);
+        process::exit(1);
+    }
+
+    if let Shutdown::TimedOut { in_flight } = server.run() {
+        println!(
+            "Error: {} connections still in flight after the {}s grace period",
+            in_flight, config.server.shutdown_grace_secs
+        );
+        process::exit(3);
+    }
+}
+
+/// The repository chosen by `database.backend`, and its pool if it has one.
+type Storage = (Box<dyn UserRepository>, Option<Arc<ConnectionPool>>);
+
+fn storage(config: &Config) -> Result<Storage, String> {
+    match config.database.backend {
+        StorageBackend::Memory => {
+            if logging::enabled(LogLevel::Warn) {
+                println!("Storing users in memory; data is lost on restart");
             }
+            Ok((Box::new(InMemoryUserRepository::new()), None))
+        }
+        StorageBackend::Postgres => {
+            let database_url = &config.database.url;
+            set_database(database_url)
+                .map_err(|e| format!("setting up database: {}", e))?;
+            let pool = ConnectionPool::new(database_url, config.pool())
+                .map_err(|e| format!("creating connection pool: {}", e))?;
+            let pool = Arc::new(pool);
+            Ok((Box::new(PostgresUserRepository::new(Arc::clone(&pool))), Some(pool)))
         }
     }
 }
+
+/// Purges expired tombstones once, for deployments that run it from cron
+/// with `purge.interval_secs = 0` on the servers.
+fn purge(config: &Config) -> Result<(), String> {
+    let (users, _) = storage(config)?;
+    let purged = purge_expired(users.as_ref(), &config.retention()).map_err(|e| e.to_string())?;
+    println!("Purged {} deleted users", purged);
+    Ok(())
+}
+
+/// Asks the server at `server.bind` for `path` and fails unless it answers
+/// 200; for `HEALTHCHECK` in images without curl.
+fn healthcheck(config: &Config, path: &str) -> Result<(), String> {
+    let address = config
+        .server
+        .bind
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .map(local_connect_address)
+        .ok_or_else(|| format!("cannot resolve {}", config.server.bind))?;
+    let mut stream = TcpStream::connect_timeout(&address, HEALTHCHECK_TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(HEALTHCHECK_TIMEOUT)).map_err(|e| e.to_string())?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address);
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|e| e.to_string())?;
+    let status = response.lines().next().unwrap_or_default();
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    println!("{} {}", status, body);
+    match status.split(' ').nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(format!("{} is not healthy", path)),
+    }
+}
+
+fn migrate(config: &Config, command: &[&str]) -> Result<(), String> {
+    if config.database.url.is_empty() {
+        return Err("database.url must be set".to_string());
+    }
+    let mut client = connect(&config.database.url).map_err(|e| e.to_string())?;
+    match command {
+        ["up"] => {
+            let applied = migrate_up(&mut client).map_err(|e| e.to_string())?;
+            for migration in &applied {
+                println!("Applied migration {} ({})", migration.version, migration.name);
+            }
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+        }
+        ["down"] | ["down", _] => {
+            let steps = match command.get(1) {
+                Some(steps) => steps.parse().map_err(|_| format!("invalid step count `{}`", steps))?,
+                None => 1,
+            };
+            let reverted = migrate_down(&mut client, steps).map_err(|e| e.to_string())?;
+            for migration in &reverted {
+                println!("Reverted migration {} ({})", migration.version, migration.name);
+            }
+            if reverted.is_empty() {
+                println!("No migrations to revert");
+            }
+        }
+        ["status"] => {
+            for status in migration_status(&mut client).map_err(|e| e.to_string())? {
+                let state = match status.state {
+                    MigrationState::Applied { applied_at } => format!("applied {}", applied_at),
+                    MigrationState::Pending => "pending".to_string(),
+                    MigrationState::Modified { applied_at } => format!("MODIFIED since applied {}", applied_at),
+                    MigrationState::Unknown { applied_at } => format!("applied {}, unknown to this binary", applied_at),
+                };
+                println!("{:>4}  {:<24}  {}", status.version, status.name, state);
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
\ No newline at end of file
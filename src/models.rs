@@ -1,8 +1,31 @@
+use chrono::{DateTime, Utc};
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Serialize,Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: Option<i32>,
     pub name: String,
     pub email: String,
+    /// Incremented by every write; the user's `ETag` is derived from it.
+    pub version: i32,
+    /// Maintained by the server; clients cannot set either timestamp.
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Set while the user is deleted; only visible with `include_deleted`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// The client-writable fields of a user, already validated.
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+}
+
+/// A search result; `score` is higher for better matches.
+#[derive(Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub user: User,
+    pub score: f32,
 }
\ No newline at end of file
@@ -0,0 +1,427 @@
+use std::fmt;
+use serde_json::{Map, Value};
+
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+pub const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The patch document itself is malformed.
+    Invalid(String),
+    /// The patch is well formed but cannot be applied to this document.
+    Failed(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Invalid(detail) | PatchError::Failed(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+/// Applies an RFC 7396 JSON Merge Patch to `target`.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Applies an RFC 6902 JSON Patch to `target`.
+///
+/// Operations are applied to a copy, so `target` is left untouched if any of
+/// them fails.
+pub fn json_patch(target: &mut Value, patch: &Value) -> Result<(), PatchError> {
+    let Value::Array(operations) = patch else {
+        return Err(PatchError::Invalid("a JSON Patch document must be an array".to_string()));
+    };
+
+    let mut document = target.clone();
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(&mut document, operation).map_err(|e| match e {
+            PatchError::Invalid(detail) => PatchError::Invalid(format!("operation {}: {}", index, detail)),
+            PatchError::Failed(detail) => PatchError::Failed(format!("operation {}: {}", index, detail)),
+        })?;
+    }
+    *target = document;
+    Ok(())
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), PatchError> {
+    let op = string_member(operation, "op")?;
+    let path = parse_pointer(string_member(operation, "path")?)?;
+
+    match op {
+        "add" => add(document, &path, value_member(operation)?.clone()),
+        "remove" => remove(document, &path).map(|_| ()),
+        "replace" if path.is_empty() => {
+            *document = value_member(operation)?.clone();
+            Ok(())
+        }
+        "replace" => {
+            remove(document, &path)?;
+            add(document, &path, value_member(operation)?.clone())
+        }
+        "move" => {
+            let from = parse_pointer(string_member(operation, "from")?)?;
+            if path.len() > from.len() && path[..from.len()] == from[..] {
+                return Err(PatchError::Invalid("cannot move a value into one of its children".to_string()));
+            }
+            let value = remove(document, &from)?;
+            add(document, &path, value)
+        }
+        "copy" => {
+            let from = parse_pointer(string_member(operation, "from")?)?;
+            let value = resolve(document, &from)?.clone();
+            add(document, &path, value)
+        }
+        "test" => {
+            if resolve(document, &path)? == value_member(operation)? {
+                Ok(())
+            } else {
+                Err(PatchError::Failed(format!("test failed at {}", pointer_to_string(&path))))
+            }
+        }
+        other => Err(PatchError::Invalid(format!("unknown op `{}`", other))),
+    }
+}
+
+fn string_member<'a>(operation: &'a Value, member: &str) -> Result<&'a str, PatchError> {
+    operation
+        .get(member)
+        .and_then(Value::as_str)
+        .ok_or_else(|| PatchError::Invalid(format!("missing string member `{}`", member)))
+}
+
+fn value_member(operation: &Value) -> Result<&Value, PatchError> {
+    operation
+        .get("value")
+        .ok_or_else(|| PatchError::Invalid("missing member `value`".to_string()))
+}
+
+/// Splits an RFC 6901 JSON Pointer into unescaped reference tokens.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(PatchError::Invalid(format!("`{}` is not a JSON Pointer", pointer)));
+    };
+    Ok(rest
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn pointer_to_string(path: &[String]) -> String {
+    path.iter()
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn resolve<'a>(document: &'a Value, path: &[String]) -> Result<&'a Value, PatchError> {
+    let mut current = document;
+    for token in path {
+        current = match current {
+            Value::Object(map) => map.get(token),
+            Value::Array(items) => array_index(token, items.len())?.and_then(|i| items.get(i)),
+            _ => None,
+        }
+        .ok_or_else(|| PatchError::Failed(format!("path {} does not exist", pointer_to_string(path))))?;
+    }
+    Ok(current)
+}
+
+fn resolve_parent<'a>(
+    document: &'a mut Value,
+    path: &'a [String],
+) -> Result<(&'a mut Value, &'a str), PatchError> {
+    let (last, parents) = path
+        .split_last()
+        .ok_or_else(|| PatchError::Failed("the whole document cannot be the target".to_string()))?;
+    let mut current = document;
+    for token in parents {
+        current = match current {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(items) => {
+                let len = items.len();
+                array_index(token, len)?.and_then(move |i| items.get_mut(i))
+            }
+            _ => None,
+        }
+        .ok_or_else(|| PatchError::Failed(format!("path {} does not exist", pointer_to_string(path))))?;
+    }
+    Ok((current, last))
+}
+
+fn add(document: &mut Value, path: &[String], value: Value) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+    let (parent, token) = resolve_parent(document, path)?;
+    match parent {
+        Value::Object(map) => {
+            map.insert(token.to_string(), value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let index = if token == "-" {
+                items.len()
+            } else {
+                match array_index(token, items.len() + 1)? {
+                    Some(index) => index,
+                    None => return Err(PatchError::Failed(format!("index {} is out of bounds", token))),
+                }
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(PatchError::Failed(format!("cannot add to {}", pointer_to_string(path)))),
+    }
+}
+
+fn remove(document: &mut Value, path: &[String]) -> Result<Value, PatchError> {
+    let missing = || PatchError::Failed(format!("path {} does not exist", pointer_to_string(path)));
+    let (parent, token) = resolve_parent(document, path)?;
+    match parent {
+        Value::Object(map) => map.remove(token).ok_or_else(missing),
+        Value::Array(items) => {
+            let index = array_index(token, items.len())?.ok_or_else(missing)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(missing()),
+    }
+}
+
+/// Parses an array index token, returning `None` when it is not below `len`.
+fn array_index(token: &str, len: usize) -> Result<Option<usize>, PatchError> {
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    if !valid {
+        return Err(PatchError::Failed(format!("`{}` is not an array index", token)));
+    }
+    Ok(token.parse::<usize>().ok().filter(|index| *index < len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(document: Value, patch: Value) -> Result<Value, PatchError> {
+        let mut document = document;
+        json_patch(&mut document, &patch).map(|_| document)
+    }
+
+    fn merged(target: Value, patch: Value) -> Value {
+        let mut target = target;
+        merge_patch(&mut target, &patch);
+        target
+    }
+
+    /// RFC 6902 Appendix A, in order; A.13 (duplicate members) is a JSON parsing concern.
+    #[test]
+    fn rfc6902_appendix_a() {
+        let succeeding = [
+            (
+                json!({"foo": "bar"}),
+                json!([{"op": "add", "path": "/baz", "value": "qux"}]),
+                json!({"baz": "qux", "foo": "bar"}),
+            ),
+            (
+                json!({"foo": ["bar", "baz"]}),
+                json!([{"op": "add", "path": "/foo/1", "value": "qux"}]),
+                json!({"foo": ["bar", "qux", "baz"]}),
+            ),
+            (
+                json!({"baz": "qux", "foo": "bar"}),
+                json!([{"op": "remove", "path": "/baz"}]),
+                json!({"foo": "bar"}),
+            ),
+            (
+                json!({"foo": ["bar", "qux", "baz"]}),
+                json!([{"op": "remove", "path": "/foo/1"}]),
+                json!({"foo": ["bar", "baz"]}),
+            ),
+            (
+                json!({"baz": "qux", "foo": "bar"}),
+                json!([{"op": "replace", "path": "/baz", "value": "boo"}]),
+                json!({"baz": "boo", "foo": "bar"}),
+            ),
+            (
+                json!({"foo": {"bar": "baz", "waldo": "fred"}, "qux": {"corge": "grault"}}),
+                json!([{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}]),
+                json!({"foo": {"bar": "baz"}, "qux": {"corge": "grault", "thud": "fred"}}),
+            ),
+            (
+                json!({"foo": ["all", "grass", "cows", "eat"]}),
+                json!([{"op": "move", "from": "/foo/1", "path": "/foo/3"}]),
+                json!({"foo": ["all", "cows", "eat", "grass"]}),
+            ),
+            (
+                json!({"baz": "qux", "foo": ["a", 2, "c"]}),
+                json!([
+                    {"op": "test", "path": "/baz", "value": "qux"},
+                    {"op": "test", "path": "/foo/1", "value": 2}
+                ]),
+                json!({"baz": "qux", "foo": ["a", 2, "c"]}),
+            ),
+            (
+                json!({"foo": "bar"}),
+                json!([{"op": "add", "path": "/child", "value": {"grandchild": {}}}]),
+                json!({"foo": "bar", "child": {"grandchild": {}}}),
+            ),
+            (
+                json!({"foo": "bar"}),
+                json!([{"op": "add", "path": "/baz", "value": "qux", "xyz": 123}]),
+                json!({"foo": "bar", "baz": "qux"}),
+            ),
+            (
+                json!({"/": 9, "~1": 10}),
+                json!([{"op": "test", "path": "/~01", "value": 10}]),
+                json!({"/": 9, "~1": 10}),
+            ),
+            (
+                json!({"foo": ["bar"]}),
+                json!([{"op": "add", "path": "/foo/-", "value": ["abc", "def"]}]),
+                json!({"foo": ["bar", ["abc", "def"]]}),
+            ),
+        ];
+        for (document, patch, expected) in succeeding {
+            assert_eq!(patched(document, patch.clone()).unwrap(), expected, "{}", patch);
+        }
+
+        let failing = [
+            (json!({"baz": "qux"}), json!([{"op": "test", "path": "/baz", "value": "bar"}])),
+            (json!({"foo": "bar"}), json!([{"op": "add", "path": "/baz/bat", "value": "qux"}])),
+            (json!({"/": 9, "~1": 10}), json!([{"op": "test", "path": "/~01", "value": "10"}])),
+        ];
+        for (document, patch) in failing {
+            assert!(matches!(patched(document, patch.clone()), Err(PatchError::Failed(_))), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn cannot_move_into_a_child() {
+        let document = json!({"a": {"b": 1}});
+        let patch = json!([{"op": "move", "from": "/a", "path": "/a/b/c"}]);
+        assert!(matches!(patched(document.clone(), patch), Err(PatchError::Invalid(_))));
+        // A sibling whose name merely starts with the same characters is fine.
+        let patch = json!([{"op": "move", "from": "/a", "path": "/ab"}]);
+        assert_eq!(patched(document, patch).unwrap(), json!({"ab": {"b": 1}}));
+    }
+
+    #[test]
+    fn dash_index_appends_only_on_add() {
+        let document = json!({"list": [1, 2]});
+        let append = json!([{"op": "add", "path": "/list/-", "value": 3}]);
+        assert_eq!(patched(document.clone(), append).unwrap(), json!({"list": [1, 2, 3]}));
+        let at_end = json!([{"op": "add", "path": "/list/2", "value": 3}]);
+        assert_eq!(patched(document.clone(), at_end).unwrap(), json!({"list": [1, 2, 3]}));
+        for patch in [
+            json!([{"op": "remove", "path": "/list/-"}]),
+            json!([{"op": "replace", "path": "/list/-", "value": 3}]),
+            json!([{"op": "add", "path": "/list/3", "value": 3}]),
+        ] {
+            assert!(matches!(patched(document.clone(), patch.clone()), Err(PatchError::Failed(_))), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn rejects_leading_zero_and_non_numeric_indices() {
+        let document = json!({"list": [1, 2]});
+        for path in ["/list/01", "/list/00", "/list/+1", "/list/-1", "/list/", "/list/1e0"] {
+            let patch = json!([{"op": "test", "path": path, "value": 2}]);
+            assert!(matches!(patched(document.clone(), patch), Err(PatchError::Failed(_))), "{}", path);
+        }
+        let patch = json!([{"op": "test", "path": "/list/0", "value": 1}]);
+        assert!(patched(document, patch).is_ok());
+    }
+
+    #[test]
+    fn pointer_escapes() {
+        let document = json!({"a/b": 1, "m~n": 2, "~1": 3});
+        let patch = json!([
+            {"op": "test", "path": "/a~1b", "value": 1},
+            {"op": "test", "path": "/m~0n", "value": 2},
+            {"op": "remove", "path": "/~01"},
+            {"op": "add", "path": "/x~1y~0z", "value": 4}
+        ]);
+        assert_eq!(patched(document, patch).unwrap(), json!({"a/b": 1, "m~n": 2, "x/y~z": 4}));
+        let invalid = json!([{"op": "test", "path": "a", "value": 1}]);
+        assert!(matches!(patched(json!({"a": 1}), invalid), Err(PatchError::Invalid(_))));
+    }
+
+    #[test]
+    fn failed_patch_leaves_target_untouched() {
+        let original = json!({"name": "Ann", "tags": ["a"]});
+        let mut document = original.clone();
+        let patch = json!([
+            {"op": "replace", "path": "/name", "value": "Bob"},
+            {"op": "add", "path": "/tags/-", "value": "b"},
+            {"op": "test", "path": "/name", "value": "Ann"}
+        ]);
+        let error = json_patch(&mut document, &patch).unwrap_err();
+        assert_eq!(error.to_string(), "operation 2: test failed at /name");
+        assert_eq!(document, original);
+    }
+
+    #[test]
+    fn malformed_operations() {
+        for patch in [
+            json!({"op": "add", "path": "/a", "value": 1}),
+            json!([{"path": "/a", "value": 1}]),
+            json!([{"op": "add", "value": 1}]),
+            json!([{"op": "add", "path": "/a"}]),
+            json!([{"op": "move", "path": "/a"}]),
+            json!([{"op": "frobnicate", "path": "/a"}]),
+        ] {
+            assert!(matches!(patched(json!({}), patch.clone()), Err(PatchError::Invalid(_))), "{}", patch);
+        }
+    }
+
+    /// RFC 7396 Appendix A.
+    #[test]
+    fn rfc7396_appendix_a() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": "b", "b": "c"}), json!({"a": null}), json!({"b": "c"})),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b", "c": null}), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ];
+        for (target, patch, expected) in cases {
+            assert_eq!(merged(target.clone(), patch.clone()), expected, "{} + {}", target, patch);
+        }
+    }
+}
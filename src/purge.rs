@@ -0,0 +1,86 @@
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chrono::Utc;
+use crate::audit::AuditContext;
+use crate::logging::{self, LogLevel};
+use crate::repository::{RepositoryError, UserRepository};
+use crate::state::AppState;
+use crate::utils::generate_request_id;
+
+pub const DEFAULT_RETENTION_DAYS: u64 = 30;
+pub const DEFAULT_PURGE_INTERVAL_SECS: u64 = 3600;
+
+/// How long deleted users stay restorable, and how often the server purges
+/// them afterwards.
+pub struct RetentionConfig {
+    pub retention: chrono::Duration,
+    /// `None` disables the background purge; the `purge` subcommand still works.
+    pub interval: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub enum PurgeError {
+    /// The retention period reaches back further than a timestamp can.
+    RetentionOutOfRange,
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for PurgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PurgeError::RetentionOutOfRange => write!(f, "retention period is out of range"),
+            PurgeError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RepositoryError> for PurgeError {
+    fn from(e: RepositoryError) -> Self {
+        PurgeError::Repository(e)
+    }
+}
+
+/// Permanently removes users deleted longer than the retention period ago.
+pub fn purge_expired(users: &dyn UserRepository, config: &RetentionConfig) -> Result<u64, PurgeError> {
+    let cutoff = Utc::now()
+        .checked_sub_signed(config.retention)
+        .ok_or(PurgeError::RetentionOutOfRange)?;
+    let context = AuditContext::system(generate_request_id());
+    Ok(users.purge_deleted(cutoff, &context)?)
+}
+
+/// Purges every `config.interval` until `stop` is signalled or its sender is
+/// dropped. `None` if the background purge is disabled.
+pub fn spawn_purger(state: Arc<AppState>, config: RetentionConfig, stop: Receiver<()>) -> Option<JoinHandle<()>> {
+    let interval = config.interval?;
+    Some(thread::spawn(move || loop {
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+        match purge_expired(state.users.as_ref(), &config) {
+            Ok(purged) if purged > 0 && logging::enabled(LogLevel::Info) => println!("Purged {} deleted users", purged),
+            Ok(_) => {}
+            Err(e) => println!("Error purging deleted users: {}", e),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InMemoryUserRepository;
+
+    #[test]
+    fn retention_beyond_representable_time_is_an_error() {
+        let config = RetentionConfig {
+            retention: chrono::Duration::MAX,
+            interval: None,
+        };
+        let result = purge_expired(&InMemoryUserRepository::new(), &config);
+        assert!(matches!(result, Err(PurgeError::RetentionOutOfRange)));
+    }
+}
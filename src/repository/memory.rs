@@ -0,0 +1,398 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, SubsecRound, Utc};
+use crate::audit::{changes, AuditAction, AuditContext, AuditEntry, AuditQuery};
+use crate::bulk::BulkOperation;
+use crate::list_query::{ListQuery, Page, Position};
+use crate::models::{NewUser, SearchHit, User};
+use super::{check_version, BulkResult, RepositoryError, UserRepository};
+
+/// Keeps users in process memory, for tests and database-less demos.
+pub struct InMemoryUserRepository {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Clone)]
+struct MemoryState {
+    users: BTreeMap<i32, User>,
+    next_id: i32,
+    audit: Vec<AuditEntry>,
+    /// Every version of every user, oldest first.
+    history: Vec<User>,
+}
+
+impl MemoryState {
+    fn email_taken(&self, email: &str, except_id: Option<i32>) -> bool {
+        self.users.values().any(|user| {
+            user.id != except_id && user.deleted_at.is_none() && user.email.to_lowercase() == email.to_lowercase()
+        })
+    }
+
+    fn create(&mut self, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError> {
+        if self.email_taken(&user.email, None) {
+            return Err(RepositoryError::Conflict { field: "email" });
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let now = now();
+        let created = User {
+            id: Some(id),
+            name: user.name.clone(),
+            email: user.email.clone(),
+            version: 1,
+            created_at: now,
+            updated_at: now,
+            deleted_at: None,
+        };
+        self.users.insert(id, created.clone());
+        self.record(AuditAction::Create, context, None, Some(&created), now);
+        Ok(created)
+    }
+
+    fn update(
+        &mut self,
+        id: i32,
+        user: &NewUser,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        let Some(before) = self.users.get(&id).filter(|user| user.deleted_at.is_none()).cloned() else {
+            return Ok(None);
+        };
+        check_version(&before, expected)?;
+        if self.email_taken(&user.email, Some(id)) {
+            return Err(RepositoryError::Conflict { field: "email" });
+        }
+        let now = now();
+        let after = User {
+            name: user.name.clone(),
+            email: user.email.clone(),
+            version: before.version + 1,
+            updated_at: now,
+            ..before.clone()
+        };
+        self.users.insert(id, after.clone());
+        self.record(AuditAction::Update, context, Some(&before), Some(&after), now);
+        Ok(Some(after))
+    }
+
+    /// Returns the tombstoned user.
+    fn delete(&mut self, id: i32, expected: Option<&[i32]>, context: &AuditContext) -> Result<Option<User>, RepositoryError> {
+        let Some(before) = self.users.get(&id).filter(|user| user.deleted_at.is_none()).cloned() else {
+            return Ok(None);
+        };
+        check_version(&before, expected)?;
+        let now = now();
+        let after = User {
+            deleted_at: Some(now),
+            version: before.version + 1,
+            updated_at: now,
+            ..before.clone()
+        };
+        self.users.insert(id, after.clone());
+        self.record(AuditAction::Delete, context, Some(&before), Some(&after), now);
+        Ok(Some(after))
+    }
+
+    /// Writes the audit entry for a mutation and, unless the user is gone, the
+    /// version it produced.
+    fn record(
+        &mut self,
+        action: AuditAction,
+        context: &AuditContext,
+        before: Option<&User>,
+        after: Option<&User>,
+        occurred_at: DateTime<Utc>,
+    ) {
+        let entry = AuditEntry {
+            id: self.audit.len() as i64 + 1,
+            user_id: after.or(before).and_then(|user| user.id).unwrap_or_default(),
+            action: action.as_str().to_string(),
+            actor: context.actor.clone(),
+            request_id: context.request_id.clone(),
+            changes: changes(before, after),
+            occurred_at,
+        };
+        self.audit.push(entry);
+        if let Some(user) = after {
+            self.history.push(user.clone());
+        }
+    }
+}
+
+impl InMemoryUserRepository {
+    pub fn new() -> InMemoryUserRepository {
+        InMemoryUserRepository {
+            state: Mutex::new(MemoryState {
+                users: BTreeMap::new(),
+                next_id: 1,
+                audit: Vec::new(),
+                history: Vec::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for InMemoryUserRepository {
+    fn default() -> Self {
+        InMemoryUserRepository::new()
+    }
+}
+
+impl UserRepository for InMemoryUserRepository {
+    fn create(&self, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError> {
+        self.lock().create(user, context)
+    }
+
+    fn get(&self, id: i32, include_deleted: bool) -> Result<Option<User>, RepositoryError> {
+        Ok(self
+            .lock()
+            .users
+            .get(&id)
+            .filter(|user| include_deleted || user.deleted_at.is_none())
+            .cloned())
+    }
+
+    fn get_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Option<User>, RepositoryError> {
+        Ok(self
+            .lock()
+            .history
+            .iter()
+            .rev()
+            .find(|user| user.id == Some(id) && user.updated_at <= as_of)
+            .cloned())
+    }
+
+    fn get_version(&self, id: i32, version: i32) -> Result<Option<User>, RepositoryError> {
+        Ok(self
+            .lock()
+            .history
+            .iter()
+            .find(|user| user.id == Some(id) && user.version == version)
+            .cloned())
+    }
+
+    fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        let state = self.lock();
+        let mut users: Vec<&User> = state
+            .users
+            .values()
+            .filter(|user| query.include_deleted || user.deleted_at.is_none())
+            .filter(|user| query.filters.iter().all(|filter| filter.matches(user)))
+            .collect();
+        users.sort_by(|a, b| query.compare(a, &query.sort_values(b)));
+        let total = query.include_total.then_some(users.len() as i64);
+
+        let limit = query.limit as usize;
+        let mut window: Vec<User> = match &query.position {
+            Position::Start => users.into_iter().take(limit + 1).cloned().collect(),
+            Position::Offset(offset) => users
+                .into_iter()
+                .skip(*offset as usize)
+                .take(limit + 1)
+                .cloned()
+                .collect(),
+            Position::After(values) => users
+                .into_iter()
+                .filter(|user| query.compare(user, values) == Ordering::Greater)
+                .take(limit + 1)
+                .cloned()
+                .collect(),
+            Position::Before(values) => users
+                .into_iter()
+                .rev()
+                .filter(|user| query.compare(user, values) == Ordering::Less)
+                .take(limit + 1)
+                .cloned()
+                .collect(),
+        };
+
+        let has_more = window.len() > limit;
+        window.truncate(limit);
+        if let Position::Before(_) = query.position {
+            window.reverse();
+        }
+
+        Ok(Page {
+            users: window,
+            has_more,
+            total,
+        })
+    }
+
+    fn search(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>, RepositoryError> {
+        let needle = text.to_lowercase();
+        let mut hits: Vec<SearchHit> = self
+            .lock()
+            .users
+            .values()
+            .filter(|user| user.deleted_at.is_none())
+            .filter_map(|user| {
+                let score = [&user.name, &user.email]
+                    .iter()
+                    .map(|field| {
+                        let field = field.to_lowercase();
+                        let contains = if field.contains(&needle) { 1.0 } else { 0.0 };
+                        // Comparing against single words approximates pg_trgm's word_similarity.
+                        let best_word = field
+                            .split(|c: char| !c.is_alphanumeric())
+                            .map(|word| trigram_similarity(&needle, word))
+                            .fold(trigram_similarity(&needle, &field), f32::max);
+                        contains + best_word
+                    })
+                    .fold(0.0, f32::max);
+                (score >= SIMILARITY_THRESHOLD).then(|| SearchHit {
+                    user: user.clone(),
+                    score,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.user.id.cmp(&b.user.id)));
+        hits.truncate(limit as usize);
+        Ok(hits)
+    }
+
+    fn update(
+        &self,
+        id: i32,
+        user: &NewUser,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.lock().update(id, user, expected, context)
+    }
+
+    fn delete(&self, id: i32, expected: Option<&[i32]>, context: &AuditContext) -> Result<bool, RepositoryError> {
+        Ok(self.lock().delete(id, expected, context)?.is_some())
+    }
+
+    fn restore(
+        &self,
+        id: i32,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut state = self.lock();
+        let Some(before) = state.users.get(&id).cloned() else {
+            return Ok(None);
+        };
+        check_version(&before, expected)?;
+        if before.deleted_at.is_none() {
+            return Ok(Some(before));
+        }
+        if state.email_taken(&before.email, Some(id)) {
+            return Err(RepositoryError::Conflict { field: "email" });
+        }
+        let now = now();
+        let after = User {
+            deleted_at: None,
+            version: before.version + 1,
+            updated_at: now,
+            ..before.clone()
+        };
+        state.users.insert(id, after.clone());
+        state.record(AuditAction::Restore, context, Some(&before), Some(&after), now);
+        Ok(Some(after))
+    }
+
+    fn purge_deleted(&self, deleted_before: DateTime<Utc>, context: &AuditContext) -> Result<u64, RepositoryError> {
+        let mut state = self.lock();
+        let expired: Vec<User> = state
+            .users
+            .values()
+            .filter(|user| user.deleted_at.is_some_and(|deleted_at| deleted_at < deleted_before))
+            .cloned()
+            .collect();
+        let now = now();
+        for user in &expired {
+            if let Some(id) = user.id {
+                state.users.remove(&id);
+                state.history.retain(|version| version.id != Some(id));
+            }
+            state.record(AuditAction::Purge, context, Some(user), None, now);
+        }
+        Ok(expired.len() as u64)
+    }
+
+    fn bulk(
+        &self,
+        operations: &[BulkOperation],
+        atomic: bool,
+        context: &AuditContext,
+    ) -> Result<Vec<BulkResult>, RepositoryError> {
+        let mut state = self.lock();
+        let snapshot = atomic.then(|| state.clone());
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = match operation {
+                BulkOperation::Create(user) => state.create(user, context).map(Some),
+                BulkOperation::Update { id, user, version } => {
+                    state.update(*id, user, version.as_ref().map(slice::from_ref), context)
+                }
+                BulkOperation::Delete { id, version } => {
+                    state.delete(*id, version.as_ref().map(slice::from_ref), context)
+                }
+            };
+            let succeeded = matches!(result, Ok(Some(_)));
+            results.push(result);
+            if !succeeded {
+                if let Some(snapshot) = snapshot {
+                    *state = snapshot;
+                    break;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    fn audit_entries(&self, user_id: Option<i32>, query: &AuditQuery) -> Result<Vec<AuditEntry>, RepositoryError> {
+        Ok(self
+            .lock()
+            .audit
+            .iter()
+            .filter(|entry| user_id.is_none() || user_id == Some(entry.user_id))
+            .filter(|entry| match query.since {
+                Some(since) => entry.occurred_at >= since,
+                None => true,
+            })
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Truncated to the microsecond resolution Postgres stores.
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(6)
+}
+
+/// pg_trgm's default `similarity_threshold`.
+const SIMILARITY_THRESHOLD: f32 = 0.3;
+
+/// Approximates pg_trgm: each alphanumeric word is padded with two leading and
+/// one trailing space, and similarity is shared trigrams over distinct trigrams.
+fn trigram_similarity(a: &str, b: &str) -> f32 {
+    let (a, b) = (trigrams(a), trigrams(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+fn trigrams(text: &str) -> BTreeSet<[char; 3]> {
+    let mut set = BTreeSet::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let padded: Vec<char> = format!("  {} ", word).chars().collect();
+        for window in padded.windows(3) {
+            set.insert([window[0], window[1], window[2]]);
+        }
+    }
+    set
+}
\ No newline at end of file
@@ -0,0 +1,123 @@
+mod memory;
+mod postgres;
+
+pub use self::memory::InMemoryUserRepository;
+pub use self::postgres::PostgresUserRepository;
+
+use std::fmt;
+use chrono::{DateTime, Utc};
+use ::postgres::error::SqlState;
+use ::postgres::Error as PostgresError;
+use crate::audit::{AuditContext, AuditEntry, AuditQuery};
+use crate::bulk::BulkOperation;
+use crate::connection_pool::PoolError;
+use crate::list_query::{ListQuery, Page};
+use crate::models::{NewUser, SearchHit, User};
+
+/// Persistence for users, independent of the backing store.
+///
+/// `update` and `delete` report a missing user through their return value rather
+/// than an error so handlers can answer 404 without inspecting error variants.
+/// Given `expected` versions, they only write if the user's current version is
+/// one of them, checked atomically with the write.
+///
+/// Every mutation records an audit entry atomically with the change it describes,
+/// and every version a user goes through is kept until the user is purged.
+///
+/// Deleted users keep a `deleted_at` tombstone until purged. Only `get` with
+/// `include_deleted`, `list` with `include_deleted`, `restore` and the history
+/// lookups see them.
+pub trait UserRepository: Send + Sync {
+    fn create(&self, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError>;
+    fn get(&self, id: i32, include_deleted: bool) -> Result<Option<User>, RepositoryError>;
+    /// The version of a user that was current at `as_of`, deleted or not.
+    fn get_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Option<User>, RepositoryError>;
+    fn get_version(&self, id: i32, version: i32) -> Result<Option<User>, RepositoryError>;
+    fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError>;
+    /// Full-text and fuzzy search over name and email, best matches first.
+    fn search(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>, RepositoryError>;
+    fn update(
+        &self,
+        id: i32,
+        user: &NewUser,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError>;
+    fn delete(&self, id: i32, expected: Option<&[i32]>, context: &AuditContext) -> Result<bool, RepositoryError>;
+    /// Clears a deleted user's tombstone. Restoring a live user returns it unchanged.
+    fn restore(
+        &self,
+        id: i32,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError>;
+    /// Permanently removes users deleted before `deleted_before`, returning how many.
+    fn purge_deleted(&self, deleted_before: DateTime<Utc>, context: &AuditContext) -> Result<u64, RepositoryError>;
+    /// Runs `operations` in order over a single connection, one result each;
+    /// `Ok(None)` means the user was not found. Per item, a failed operation
+    /// changes nothing and the rest still run. Atomically, the first failure
+    /// undoes every operation and ends the batch, so the results stop there.
+    fn bulk(
+        &self,
+        operations: &[BulkOperation],
+        atomic: bool,
+        context: &AuditContext,
+    ) -> Result<Vec<BulkResult>, RepositoryError>;
+    /// Audit entries, oldest first, optionally only those of one user.
+    fn audit_entries(&self, user_id: Option<i32>, query: &AuditQuery) -> Result<Vec<AuditEntry>, RepositoryError>;
+}
+
+/// The user a bulk operation created, updated or deleted.
+pub type BulkResult = Result<Option<User>, RepositoryError>;
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A unique constraint on `field` rejected the write.
+    Conflict { field: &'static str },
+    /// The user exists but its version is not one of those expected.
+    VersionMismatch,
+    Pool(PoolError),
+    Database(PostgresError),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Conflict { field } => write!(f, "duplicate value for unique field {}", field),
+            RepositoryError::VersionMismatch => write!(f, "user was modified concurrently"),
+            RepositoryError::Pool(e) => write!(f, "{}", e),
+            RepositoryError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<PoolError> for RepositoryError {
+    fn from(e: PoolError) -> Self {
+        RepositoryError::Pool(e)
+    }
+}
+
+impl From<PostgresError> for RepositoryError {
+    fn from(e: PostgresError) -> Self {
+        let unique_field = e
+            .as_db_error()
+            .filter(|db| *db.code() == SqlState::UNIQUE_VIOLATION)
+            .map(|db| match db.constraint() {
+                Some("users_email_lower_key") => "email",
+                Some("users_pkey") => "id",
+                _ => "unknown",
+            });
+        match unique_field {
+            Some(field) => RepositoryError::Conflict { field },
+            None => RepositoryError::Database(e),
+        }
+    }
+}
+
+/// Fails with `VersionMismatch` unless `user` has one of the `expected` versions.
+fn check_version(user: &User, expected: Option<&[i32]>) -> Result<(), RepositoryError> {
+    match expected {
+        Some(versions) if !versions.contains(&user.version) => Err(RepositoryError::VersionMismatch),
+        _ => Ok(()),
+    }
+}
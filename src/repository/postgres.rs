@@ -0,0 +1,515 @@
+use std::slice;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use postgres::types::ToSql;
+use postgres::{Row, Transaction};
+use crate::audit::{changes, AuditAction, AuditContext, AuditEntry, AuditQuery};
+use crate::bulk::BulkOperation;
+use crate::connection_pool::ConnectionPool;
+use crate::list_query::{Filter, ListQuery, Page, Position, SortKey, SortValue};
+use crate::models::{NewUser, SearchHit, User};
+use super::{check_version, BulkResult, RepositoryError, UserRepository};
+
+pub struct PostgresUserRepository {
+    pool: Arc<ConnectionPool>,
+}
+
+impl PostgresUserRepository {
+    pub fn new(pool: Arc<ConnectionPool>) -> PostgresUserRepository {
+        PostgresUserRepository { pool }
+    }
+}
+
+const USER_COLUMNS: &str = "id, name, email, version, created_at, updated_at, deleted_at";
+const HISTORY_COLUMNS: &str = "user_id AS id, name, email, version, created_at, updated_at, deleted_at";
+
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get("id"),
+        name: row.get("name"),
+        email: row.get("email"),
+        version: row.get("version"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        deleted_at: row.get("deleted_at"),
+    }
+}
+
+impl UserRepository for PostgresUserRepository {
+    fn create(&self, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let created = create_in(&mut transaction, user, context)?;
+        transaction.commit()?;
+        Ok(created)
+    }
+
+    fn get(&self, id: i32, include_deleted: bool) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let row = client.query_opt(
+            &format!("SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)", USER_COLUMNS),
+            &[&id, &include_deleted],
+        )?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    fn get_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let row = client.query_opt(
+            &format!(
+                "SELECT {} FROM users_history WHERE user_id = $1 AND updated_at <= $2 ORDER BY version DESC LIMIT 1",
+                HISTORY_COLUMNS
+            ),
+            &[&id, &as_of],
+        )?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    fn get_version(&self, id: i32, version: i32) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let row = client.query_opt(
+            &format!("SELECT {} FROM users_history WHERE user_id = $1 AND version = $2", HISTORY_COLUMNS),
+            &[&id, &version],
+        )?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        let mut client = self.pool.get()?;
+
+        let filtered = |select: &str| {
+            let mut sql = SqlBuilder::new(select);
+            if !query.include_deleted {
+                sql.push_condition("deleted_at IS NULL");
+            }
+            sql.push_filters(&query.filters);
+            sql
+        };
+
+        let total = if query.include_total {
+            let sql = filtered("SELECT COUNT(*) FROM users");
+            Some(client.query_one(&sql.text, &sql.params())?.get(0))
+        } else {
+            None
+        };
+
+        let mut sql = filtered(&format!("SELECT {} FROM users", USER_COLUMNS));
+        let backwards = matches!(query.position, Position::Before(_));
+        if let Position::After(values) | Position::Before(values) = &query.position {
+            sql.push_keyset(&query.sort, values, backwards);
+        }
+        sql.push_order(&query.sort, backwards);
+        // One extra row tells us whether another page follows.
+        let limit = sql.bind(query.limit + 1);
+        sql.text.push_str(&format!(" LIMIT {}", limit));
+        if let Position::Offset(offset) = query.position {
+            let offset = sql.bind(offset);
+            sql.text.push_str(&format!(" OFFSET {}", offset));
+        }
+
+        let mut rows = client.query(&sql.text, &sql.params())?;
+        let has_more = rows.len() as i64 > query.limit;
+        rows.truncate(query.limit as usize);
+        if backwards {
+            rows.reverse();
+        }
+
+        Ok(Page {
+            users: rows.iter().map(user_from_row).collect(),
+            has_more,
+            total,
+        })
+    }
+
+    fn search(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        // The WHERE clause mirrors the GIN indexes created in `set_database`:
+        // full-text on the combined document and trigram (word) similarity per
+        // column, so typos and fragments still match.
+        let rows = client.query(
+            "SELECT id, name, email, version, created_at, updated_at, deleted_at,
+                    (ts_rank(to_tsvector('simple', name || ' ' || email), plainto_tsquery('simple', $1))
+                     + GREATEST(word_similarity($1, name), word_similarity($1, email),
+                                similarity(name, $1), similarity(email, $1)))::real AS score
+             FROM users
+             WHERE deleted_at IS NULL
+               AND (to_tsvector('simple', name || ' ' || email) @@ plainto_tsquery('simple', $1)
+                    OR $1 <% name OR $1 <% email
+                    OR name % $1 OR email % $1)
+             ORDER BY score DESC, id
+             LIMIT $2",
+            &[&text, &limit],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| SearchHit {
+                user: user_from_row(row),
+                score: row.get("score"),
+            })
+            .collect())
+    }
+
+    fn update(
+        &self,
+        id: i32,
+        user: &NewUser,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let updated = update_in(&mut transaction, id, user, expected, context)?;
+        transaction.commit()?;
+        Ok(updated)
+    }
+
+    fn delete(&self, id: i32, expected: Option<&[i32]>, context: &AuditContext) -> Result<bool, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let deleted = delete_in(&mut transaction, id, expected, context)?;
+        transaction.commit()?;
+        Ok(deleted.is_some())
+    }
+
+    fn restore(
+        &self,
+        id: i32,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let Some(before) = lock_user(&mut transaction, id, expected, true)? else {
+            return Ok(None);
+        };
+        if before.deleted_at.is_none() {
+            return Ok(Some(before));
+        }
+        let row = transaction.query_one(
+            &format!(
+                "UPDATE users SET deleted_at = NULL, version = version + 1, updated_at = now()
+                 WHERE id = $1 RETURNING {}",
+                USER_COLUMNS
+            ),
+            &[&id],
+        )?;
+        let after = user_from_row(&row);
+        record(&mut transaction, AuditAction::Restore, context, Some(&before), Some(&after))?;
+        transaction.commit()?;
+        Ok(Some(after))
+    }
+
+    fn purge_deleted(&self, deleted_before: DateTime<Utc>, context: &AuditContext) -> Result<u64, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let rows = transaction.query(
+            &format!("DELETE FROM users WHERE deleted_at < $1 RETURNING {}", USER_COLUMNS),
+            &[&deleted_before],
+        )?;
+        for row in &rows {
+            record(&mut transaction, AuditAction::Purge, context, Some(&user_from_row(row)), None)?;
+        }
+        transaction.commit()?;
+        Ok(rows.len() as u64)
+    }
+
+    fn bulk(
+        &self,
+        operations: &[BulkOperation],
+        atomic: bool,
+        context: &AuditContext,
+    ) -> Result<Vec<BulkResult>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            // A failed statement aborts the whole transaction, so each
+            // operation gets a savepoint to roll back to.
+            let mut savepoint = transaction.transaction()?;
+            let result = match operation {
+                BulkOperation::Create(user) => create_in(&mut savepoint, user, context).map(Some),
+                BulkOperation::Update { id, user, version } => {
+                    update_in(&mut savepoint, *id, user, version.as_ref().map(slice::from_ref), context)
+                }
+                BulkOperation::Delete { id, version } => {
+                    delete_in(&mut savepoint, *id, version.as_ref().map(slice::from_ref), context)
+                }
+            };
+            let succeeded = matches!(result, Ok(Some(_)));
+            if succeeded {
+                savepoint.commit()?;
+            } else {
+                savepoint.rollback()?;
+            }
+            results.push(result);
+            if atomic && !succeeded {
+                // Dropping the transaction rolls back everything before it.
+                return Ok(results);
+            }
+        }
+        transaction.commit()?;
+        Ok(results)
+    }
+
+    fn audit_entries(&self, user_id: Option<i32>, query: &AuditQuery) -> Result<Vec<AuditEntry>, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let rows = client.query(
+            "SELECT id, user_id, action, actor, request_id, changes, occurred_at FROM audit_log
+             WHERE ($1::int IS NULL OR user_id = $1) AND ($2::timestamptz IS NULL OR occurred_at >= $2)
+             ORDER BY occurred_at, id
+             LIMIT $3",
+            &[&user_id, &query.since, &query.limit],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| AuditEntry {
+                id: row.get("id"),
+                user_id: row.get("user_id"),
+                action: row.get("action"),
+                actor: row.get("actor"),
+                request_id: row.get("request_id"),
+                changes: row.get("changes"),
+                occurred_at: row.get("occurred_at"),
+            })
+            .collect())
+    }
+}
+
+fn create_in(transaction: &mut Transaction, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError> {
+    let row = transaction.query_one(
+        &format!("INSERT INTO users (name, email) VALUES ($1, $2) RETURNING {}", USER_COLUMNS),
+        &[&user.name, &user.email],
+    )?;
+    let created = user_from_row(&row);
+    record(transaction, AuditAction::Create, context, None, Some(&created))?;
+    Ok(created)
+}
+
+fn update_in(
+    transaction: &mut Transaction,
+    id: i32,
+    user: &NewUser,
+    expected: Option<&[i32]>,
+    context: &AuditContext,
+) -> Result<Option<User>, RepositoryError> {
+    let Some(before) = lock_user(transaction, id, expected, false)? else {
+        return Ok(None);
+    };
+    let row = transaction.query_one(
+        &format!(
+            "UPDATE users SET name = $1, email = $2, version = version + 1, updated_at = now()
+             WHERE id = $3 RETURNING {}",
+            USER_COLUMNS
+        ),
+        &[&user.name, &user.email, &id],
+    )?;
+    let after = user_from_row(&row);
+    record(transaction, AuditAction::Update, context, Some(&before), Some(&after))?;
+    Ok(Some(after))
+}
+
+/// Returns the tombstoned user.
+fn delete_in(
+    transaction: &mut Transaction,
+    id: i32,
+    expected: Option<&[i32]>,
+    context: &AuditContext,
+) -> Result<Option<User>, RepositoryError> {
+    let Some(before) = lock_user(transaction, id, expected, false)? else {
+        return Ok(None);
+    };
+    let row = transaction.query_one(
+        &format!(
+            "UPDATE users SET deleted_at = now(), version = version + 1, updated_at = now()
+             WHERE id = $1 RETURNING {}",
+            USER_COLUMNS
+        ),
+        &[&id],
+    )?;
+    let after = user_from_row(&row);
+    record(transaction, AuditAction::Delete, context, Some(&before), Some(&after))?;
+    Ok(Some(after))
+}
+
+/// Loads a user and locks its row until the transaction ends, so the version
+/// checked here is still current when the caller writes.
+fn lock_user(
+    transaction: &mut Transaction,
+    id: i32,
+    expected: Option<&[i32]>,
+    include_deleted: bool,
+) -> Result<Option<User>, RepositoryError> {
+    let row = transaction.query_opt(&format!("SELECT {} FROM users WHERE id = $1 FOR UPDATE", USER_COLUMNS), &[&id])?;
+    let Some(user) = row
+        .as_ref()
+        .map(user_from_row)
+        .filter(|user| include_deleted || user.deleted_at.is_none())
+    else {
+        return Ok(None);
+    };
+    check_version(&user, expected)?;
+    Ok(Some(user))
+}
+
+/// Writes the audit entry for a mutation and, unless the user is gone, the
+/// version it produced.
+fn record(
+    transaction: &mut Transaction,
+    action: AuditAction,
+    context: &AuditContext,
+    before: Option<&User>,
+    after: Option<&User>,
+) -> Result<(), RepositoryError> {
+    let user_id = after.or(before).and_then(|user| user.id).unwrap_or_default();
+    transaction.execute(
+        "INSERT INTO audit_log (user_id, action, actor, request_id, changes) VALUES ($1, $2, $3, $4, $5)",
+        &[&user_id, &action.as_str(), &context.actor, &context.request_id, &changes(before, after)],
+    )?;
+    if let Some(user) = after {
+        transaction.execute(
+            "INSERT INTO users_history (user_id, version, name, email, created_at, updated_at, deleted_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &user_id,
+                &user.version,
+                &user.name,
+                &user.email,
+                &user.created_at,
+                &user.updated_at,
+                &user.deleted_at,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Accumulates a parameterised statement. Values only ever travel as bind
+/// parameters; the SQL text is built from whitelisted column names alone.
+struct SqlBuilder {
+    text: String,
+    values: Vec<Box<dyn ToSql + Sync>>,
+    has_where: bool,
+}
+
+impl SqlBuilder {
+    fn new(text: &str) -> SqlBuilder {
+        SqlBuilder {
+            text: text.to_string(),
+            values: Vec::new(),
+            has_where: false,
+        }
+    }
+
+    fn bind<T: ToSql + Sync + 'static>(&mut self, value: T) -> String {
+        self.values.push(Box::new(value));
+        format!("${}", self.values.len())
+    }
+
+    fn bind_sort_value(&mut self, value: &SortValue) -> String {
+        match value {
+            SortValue::Int(i) => self.bind(*i),
+            SortValue::Text(s) => self.bind(s.clone()),
+        }
+    }
+
+    fn push_condition(&mut self, condition: &str) {
+        self.text.push_str(if self.has_where { " AND " } else { " WHERE " });
+        self.text.push_str(condition);
+        self.has_where = true;
+    }
+
+    fn push_filters(&mut self, filters: &[Filter]) {
+        for filter in filters {
+            let condition = match filter {
+                Filter::NameEquals(value) => format!("name = {}", self.bind(value.clone())),
+                Filter::NameLike(pattern) => format!("name ILIKE {}", self.bind(pattern.clone())),
+                // Matches the case-insensitive unique index on email.
+                Filter::EmailEquals(value) => format!("LOWER(email) = LOWER({})", self.bind(value.clone())),
+                Filter::EmailLike(pattern) => format!("email ILIKE {}", self.bind(pattern.clone())),
+            };
+            self.push_condition(&condition);
+        }
+    }
+
+    /// Restricts rows to those sorting after (or, `backwards`, before) `values`:
+    /// `(a > x) OR (a = x AND b > y) OR ...`, with each comparison flipped for
+    /// descending keys.
+    fn push_keyset(&mut self, sort: &[SortKey], values: &[SortValue], backwards: bool) {
+        let mut alternatives = Vec::new();
+        for (i, key) in sort.iter().enumerate() {
+            let mut terms = Vec::new();
+            for (equal_key, value) in sort[..i].iter().zip(values) {
+                let placeholder = self.bind_sort_value(value);
+                terms.push(format!("{} = {}", equal_key.field.sort_expression(), placeholder));
+            }
+            let operator = if key.descending != backwards { "<" } else { ">" };
+            let placeholder = self.bind_sort_value(&values[i]);
+            terms.push(format!("{} {} {}", key.field.sort_expression(), operator, placeholder));
+            alternatives.push(format!("({})", terms.join(" AND ")));
+        }
+        self.push_condition(&format!("({})", alternatives.join(" OR ")));
+    }
+
+    fn push_order(&mut self, sort: &[SortKey], backwards: bool) {
+        let order: Vec<String> = sort
+            .iter()
+            .map(|key| {
+                let direction = if key.descending != backwards { "DESC" } else { "ASC" };
+                format!("{} {}", key.field.sort_expression(), direction)
+            })
+            .collect();
+        self.text.push_str(&format!(" ORDER BY {}", order.join(", ")));
+    }
+
+    fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.values.iter().map(|value| value.as_ref()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list_query::{SortField, SortKey};
+
+    fn sort() -> Vec<SortKey> {
+        vec![
+            SortKey {
+                field: SortField::Name,
+                descending: true,
+            },
+            SortKey {
+                field: SortField::Id,
+                descending: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn keyset_and_order_compare_text_by_code_point() {
+        let values = [SortValue::Text("Ann".to_string()), SortValue::Int(4)];
+        let mut sql = SqlBuilder::new("SELECT id FROM users");
+        sql.push_keyset(&sort(), &values, false);
+        sql.push_order(&sort(), false);
+        assert_eq!(
+            sql.text,
+            "SELECT id FROM users WHERE ((name COLLATE \"C\" < $1) OR (name COLLATE \"C\" = $2 AND id > $3)) \
+             ORDER BY name COLLATE \"C\" DESC, id ASC"
+        );
+        assert_eq!(sql.values.len(), 3);
+    }
+
+    #[test]
+    fn before_flips_comparisons_and_order() {
+        let values = [SortValue::Text("Ann".to_string()), SortValue::Int(4)];
+        let mut sql = SqlBuilder::new("SELECT id FROM users");
+        sql.push_condition("deleted_at IS NULL");
+        sql.push_keyset(&sort(), &values, true);
+        sql.push_order(&sort(), true);
+        assert_eq!(
+            sql.text,
+            "SELECT id FROM users WHERE deleted_at IS NULL \
+             AND ((name COLLATE \"C\" > $1) OR (name COLLATE \"C\" = $2 AND id < $3)) \
+             ORDER BY name COLLATE \"C\" ASC, id DESC"
+        );
+    }
+}
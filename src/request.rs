@@ -0,0 +1,587 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::{Duration, Instant};
+use crate::utils::{generate_request_id, percent_decode};
+
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Request {
+    pub method: String,
+    /// `HTTP/1.1` or `HTTP/1.0`.
+    pub version: String,
+    pub path: String,
+    /// The raw query string, without the leading `?`.
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The client's `X-Request-Id` if usable, else a fresh one; echoed in the
+    /// response and recorded in the audit log.
+    pub id: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client wants to send more requests on this connection:
+    /// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only on request.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case("Connection"))
+                .flat_map(|(_, value)| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if has_token("close") {
+            return false;
+        }
+        self.version == "HTTP/1.1" || has_token("keep-alive")
+    }
+
+    /// Decoded `name=value` pairs of the query string, in order of appearance.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query
+            .as_deref()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_query_component(name), decode_query_component(value))
+            })
+            .collect()
+    }
+}
+
+/// Client ids end up in logs and headers, so only short, plain tokens are kept.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    percent_decode(&component).unwrap_or(component)
+}
+
+#[derive(Clone, Copy)]
+pub struct RequestLimits {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    /// How long an open connection may sit idle between requests; `None`
+    /// closes every connection after its first response.
+    pub keep_alive_timeout: Option<Duration>,
+    /// How long a client has to send a whole request, head and body, once it
+    /// started, however slowly the bytes trickle in.
+    pub request_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            keep_alive_timeout: Some(DEFAULT_KEEP_ALIVE_TIMEOUT),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The peer closed the connection before sending anything.
+    ConnectionClosed,
+    Io(io::Error),
+    Malformed(&'static str),
+    /// The request took longer than `RequestLimits::request_timeout`, or the
+    /// client went quiet in the middle of it.
+    TimedOut,
+    HeadersTooLarge,
+    BodyTooLarge,
+    UnsupportedTransferEncoding,
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => RequestError::TimedOut,
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+/// A connection `read_request` can put a deadline on.
+pub trait Deadline {
+    /// Makes reads fail with `TimedOut` once `deadline` has passed; `None`
+    /// lifts the deadline.
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+}
+
+enum BodyLength {
+    None,
+    Fixed(usize),
+    Chunked,
+}
+
+/// Reads one complete request (request line, headers and body) from the stream,
+/// within `limits.request_timeout`.
+///
+/// The reader is only advanced past the bytes belonging to this request, so any
+/// data the client sent after it stays buffered in `reader`.
+pub fn read_request<S: Read + Write + Deadline>(
+    reader: &mut BufReader<S>,
+    limits: &RequestLimits,
+) -> Result<Request, RequestError> {
+    reader.get_mut().set_deadline(Instant::now().checked_add(limits.request_timeout));
+    let request = read_request_within_deadline(reader, limits);
+    reader.get_mut().set_deadline(None);
+    request
+}
+
+fn read_request_within_deadline<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    limits: &RequestLimits,
+) -> Result<Request, RequestError> {
+    let mut head_budget = limits.max_header_bytes;
+
+    let request_line = loop {
+        match read_line(reader, &mut head_budget)? {
+            None => return Err(RequestError::ConnectionClosed),
+            // RFC 9112 section 2.2: ignore at least one empty line before the request line.
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && !target.is_empty() =>
+        {
+            (method.to_string(), target, version.to_string())
+        }
+        _ => return Err(RequestError::Malformed("invalid request line")),
+    };
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(RequestError::Malformed("unsupported HTTP version"));
+    }
+
+    // The query string is not part of the path the router matches against.
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    let headers = read_headers(reader, &mut head_budget)?;
+    let mut request = Request {
+        method,
+        version,
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+        id: String::new(),
+    };
+    request.id = match request.header("X-Request-Id").map(str::trim) {
+        Some(id) if is_valid_request_id(id) => id.to_string(),
+        _ => generate_request_id(),
+    };
+
+    let body_length = body_length(&request)?;
+    if let BodyLength::Fixed(length) = body_length {
+        if length > limits.max_body_bytes {
+            return Err(RequestError::BodyTooLarge);
+        }
+    }
+    if !matches!(body_length, BodyLength::None)
+        && request
+            .header("Expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+    {
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+
+    request.body = match body_length {
+        BodyLength::None => Vec::new(),
+        BodyLength::Fixed(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).map_err(truncated)?;
+            body
+        }
+        BodyLength::Chunked => read_chunked_body(reader, limits, &mut head_budget)?,
+    };
+
+    Ok(request)
+}
+
+fn body_length(request: &Request) -> Result<BodyLength, RequestError> {
+    let transfer_encoding = request.header("Transfer-Encoding");
+    let content_lengths: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    if let Some(encoding) = transfer_encoding {
+        // A request carrying both framings is a classic smuggling vector; refuse it.
+        if !content_lengths.is_empty() {
+            return Err(RequestError::Malformed("both Transfer-Encoding and Content-Length present"));
+        }
+        // Only plain chunked framing is supported; compressed transfer codings are not.
+        if !encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(RequestError::UnsupportedTransferEncoding);
+        }
+        return Ok(BodyLength::Chunked);
+    }
+
+    let mut length = None;
+    for value in content_lengths {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RequestError::Malformed("invalid Content-Length"));
+        }
+        // All digits, so the only way parsing fails is overflow.
+        let parsed: usize = value.parse().map_err(|_| RequestError::BodyTooLarge)?;
+        match length {
+            Some(previous) if previous != parsed => {
+                return Err(RequestError::Malformed("conflicting Content-Length values"))
+            }
+            _ => length = Some(parsed),
+        }
+    }
+
+    Ok(match length {
+        Some(0) | None => BodyLength::None,
+        Some(length) => BodyLength::Fixed(length),
+    })
+}
+
+fn read_headers<R: BufRead>(
+    reader: &mut R,
+    head_budget: &mut usize,
+) -> Result<Vec<(String, String)>, RequestError> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, head_budget)?
+            .ok_or(RequestError::Malformed("connection closed inside headers"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err(RequestError::Malformed("obsolete header line folding"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(RequestError::Malformed("header line without a colon"))?;
+        if name.is_empty() || name.ends_with(' ') || name.ends_with('\t') {
+            return Err(RequestError::Malformed("invalid header name"));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+}
+
+fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    limits: &RequestLimits,
+    head_budget: &mut usize,
+) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::new();
+    loop {
+        let mut line_budget = limits.max_header_bytes;
+        let line = read_line(reader, &mut line_budget)?
+            .ok_or(RequestError::Malformed("connection closed inside chunked body"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| RequestError::Malformed("invalid chunk size"))?;
+
+        if size == 0 {
+            // Trailer fields count against the header limit and are otherwise discarded.
+            read_headers(reader, head_budget)?;
+            return Ok(body);
+        }
+        if size > limits.max_body_bytes - body.len() {
+            return Err(RequestError::BodyTooLarge);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).map_err(truncated)?;
+
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).map_err(truncated)?;
+        if &crlf != b"\r\n" {
+            return Err(RequestError::Malformed("chunk not terminated by CRLF"));
+        }
+    }
+}
+
+/// Reads a single CRLF (or bare LF) terminated line, charging its length to `budget`.
+///
+/// Returns `Ok(None)` if the stream ends before any byte of the line was read.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    *budget -= read;
+
+    if line.pop() != Some(b'\n') {
+        return Err(RequestError::Malformed("connection closed mid-line"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| RequestError::Malformed("non UTF-8 bytes in request head"))
+}
+
+fn truncated(e: io::Error) -> RequestError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => RequestError::Malformed("body shorter than declared"),
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its input a few bytes per `read`, as a slow client would, and
+    /// records anything the parser writes back.
+    struct Client {
+        input: Vec<u8>,
+        position: usize,
+        chunk: usize,
+        written: Vec<u8>,
+        deadline: Option<Instant>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let end = (self.position + self.chunk.min(buf.len())).min(self.input.len());
+            let read = end - self.position;
+            buf[..read].copy_from_slice(&self.input[self.position..end]);
+            self.position = end;
+            Ok(read)
+        }
+    }
+
+    impl Deadline for Client {
+        fn set_deadline(&mut self, deadline: Option<Instant>) {
+            self.deadline = deadline;
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reader(input: &str, chunk: usize) -> BufReader<Client> {
+        BufReader::new(Client {
+            input: input.as_bytes().to_vec(),
+            position: 0,
+            chunk,
+            written: Vec::new(),
+            deadline: None,
+        })
+    }
+
+    fn parse_with(input: &str, limits: &RequestLimits) -> Result<Request, RequestError> {
+        read_request(&mut reader(input, usize::MAX), limits)
+    }
+
+    fn parse(input: &str) -> Result<Request, RequestError> {
+        parse_with(input, &RequestLimits::default())
+    }
+
+    fn small_limits() -> RequestLimits {
+        RequestLimits {
+            max_header_bytes: 64,
+            max_body_bytes: 8,
+            ..RequestLimits::default()
+        }
+    }
+
+    fn malformed(result: Result<Request, RequestError>) -> &'static str {
+        match result {
+            Err(RequestError::Malformed(reason)) => reason,
+            Err(e) => panic!("expected Malformed, got {:?}", e),
+            Ok(_) => panic!("expected Malformed, got a request"),
+        }
+    }
+
+    #[test]
+    fn parses_request_line_headers_and_body() {
+        let request = parse("POST /users?page=2 HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/users");
+        assert_eq!(request.query.as_deref(), Some("page=2"));
+        assert_eq!(request.header("host"), Some("x"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn request_split_across_reads() {
+        let input = "PUT /users/1 HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world";
+        let request = read_request(&mut reader(input, 3), &RequestLimits::default()).unwrap();
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn leaves_pipelined_request_buffered() {
+        let mut reader = reader("GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n", 7);
+        let limits = RequestLimits::default();
+        assert_eq!(read_request(&mut reader, &limits).unwrap().path, "/a");
+        assert_eq!(read_request(&mut reader, &limits).unwrap().path, "/b");
+        assert!(matches!(read_request(&mut reader, &limits), Err(RequestError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn chunked_body() {
+        let input = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(parse(input).unwrap().body, b"hello world");
+        let request = read_request(&mut reader(input, 2), &RequestLimits::default()).unwrap();
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn chunked_body_errors() {
+        let bad_size = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert_eq!(malformed(parse(bad_size)), "invalid chunk size");
+        let no_crlf = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabX\r\n0\r\n\r\n";
+        assert_eq!(malformed(parse(no_crlf)), "chunk not terminated by CRLF");
+        let truncated = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab";
+        assert_eq!(malformed(parse(truncated)), "body shorter than declared");
+    }
+
+    #[test]
+    fn unsupported_transfer_encoding() {
+        let input = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert!(matches!(parse(input), Err(RequestError::UnsupportedTransferEncoding)));
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_with_content_length() {
+        let input = "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(malformed(parse(input)), "both Transfer-Encoding and Content-Length present");
+    }
+
+    #[test]
+    fn duplicate_content_length() {
+        let same = "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(parse(same).unwrap().body, b"ok");
+        let listed = "POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nok";
+        assert_eq!(parse(listed).unwrap().body, b"ok");
+
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nok!";
+        assert_eq!(malformed(parse(conflicting)), "conflicting Content-Length values");
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 2, 3\r\n\r\nok!";
+        assert_eq!(malformed(parse(conflicting)), "conflicting Content-Length values");
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        for value in ["", "-1", "+2", "0x10", "1 2"] {
+            let input = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", value);
+            assert_eq!(malformed(parse(&input)), "invalid Content-Length", "{:?}", value);
+        }
+        let huge = "POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert!(matches!(parse(huge), Err(RequestError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn body_limit() {
+        let limits = small_limits();
+        let at_limit = "POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678";
+        assert_eq!(parse_with(at_limit, &limits).unwrap().body, b"12345678");
+        let fixed = "POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789";
+        assert!(matches!(parse_with(fixed, &limits), Err(RequestError::BodyTooLarge)));
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n4\r\n6789\r\n0\r\n\r\n";
+        assert!(matches!(parse_with(chunked, &limits), Err(RequestError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn header_limit() {
+        let limits = small_limits();
+        let long_header = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(64));
+        assert!(matches!(parse_with(&long_header, &limits), Err(RequestError::HeadersTooLarge)));
+        // The limit covers the whole head, not each line.
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(8));
+        assert!(matches!(parse_with(&many_headers, &limits), Err(RequestError::HeadersTooLarge)));
+        let trailers = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Trailer: aaaaaaaaaaaa\r\n\r\n";
+        assert!(matches!(parse_with(trailers, &limits), Err(RequestError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn malformed_heads() {
+        assert_eq!(malformed(parse("GET /\r\n\r\n")), "invalid request line");
+        assert_eq!(malformed(parse("GET / HTTP/2.0\r\n\r\n")), "unsupported HTTP version");
+        assert_eq!(malformed(parse("GET / HTTP/1.1\r\nX-A 1\r\n\r\n")), "header line without a colon");
+        assert_eq!(malformed(parse("GET / HTTP/1.1\r\nX-A : 1\r\n\r\n")), "invalid header name");
+        assert_eq!(malformed(parse("GET / HTTP/1.1\r\nX-A: 1\r\n folded\r\n\r\n")), "obsolete header line folding");
+        assert_eq!(malformed(parse("GET / HTTP/1.1\r\nX-A: 1\r\n")), "connection closed inside headers");
+    }
+
+    #[test]
+    fn empty_stream_is_connection_closed() {
+        assert!(matches!(parse(""), Err(RequestError::ConnectionClosed)));
+        assert_eq!(parse("\r\nGET / HTTP/1.1\r\n\r\n").unwrap().path, "/");
+    }
+
+    #[test]
+    fn expect_continue_is_answered_before_the_body() {
+        let mut reader = reader("POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok", 64);
+        read_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(reader.get_ref().written, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn deadline_covers_the_whole_request() {
+        let limits = RequestLimits {
+            request_timeout: Duration::ZERO,
+            ..RequestLimits::default()
+        };
+        let mut late = reader("GET / HTTP/1.1\r\n\r\n", 4);
+        assert!(matches!(read_request(&mut late, &limits), Err(RequestError::TimedOut)));
+        assert!(late.get_ref().deadline.is_none());
+
+        let mut on_time = reader("GET / HTTP/1.1\r\n\r\n", 4);
+        read_request(&mut on_time, &RequestLimits::default()).unwrap();
+        assert!(on_time.get_ref().deadline.is_none());
+    }
+
+    #[test]
+    fn request_id() {
+        assert_eq!(parse("GET / HTTP/1.1\r\nX-Request-Id: abc-1\r\n\r\n").unwrap().id, "abc-1");
+        let generated = parse("GET / HTTP/1.1\r\nX-Request-Id: bad id\r\n\r\n").unwrap().id;
+        assert_ne!(generated, "bad id");
+        assert!(!generated.is_empty());
+    }
+}
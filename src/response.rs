@@ -0,0 +1,114 @@
+use std::io::{self, Write};
+use chrono::Utc;
+use crate::conditional::http_date;
+use crate::constants::{JSON_CONTENT_TYPE, SERVER_NAME, TEXT_CONTENT_TYPE};
+
+/// An HTTP response under construction.
+///
+/// `Content-Length`, `Date` and `Server` are added when it is written, so
+/// handlers only set the headers that are specific to them.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status).with_body(JSON_CONTENT_TYPE, body)
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status).with_body(TEXT_CONTENT_TYPE, body)
+    }
+
+    pub fn with_body(self, content_type: &str, body: impl Into<String>) -> Response {
+        let mut response = self.header("Content-Type", content_type);
+        response.body = body.into();
+        response
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn headers<'a>(self, headers: impl IntoIterator<Item = (&'a str, String)>) -> Response {
+        headers
+            .into_iter()
+            .fold(self, |response, (name, value)| response.header(name, value))
+    }
+
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the response announces that the connection ends after it.
+    pub fn closes_connection(&self) -> bool {
+        self.header_value("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+
+    /// Writes the response in one go. With `head_only`, as for a HEAD request,
+    /// the headers still describe the body that was left out.
+    pub fn write_to<W: Write>(&self, writer: &mut W, head_only: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Date: {}\r\nServer: {}\r\n", http_date(&Utc::now()), SERVER_NAME));
+        // RFC 9110 section 8.6: these never carry a body or its length.
+        let bodiless = self.status < 200 || self.status == 204 || self.status == 304;
+        if !bodiless {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        if !bodiless && !head_only {
+            bytes.extend_from_slice(self.body.as_bytes());
+        }
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        207 => "Multi-Status",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        424 => "Failed Dependency",
+        428 => "Precondition Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::error::AppError;
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::percent_decode;
+
+pub type HandlerResult = Result<Response, AppError>;
+
+pub type Handler<S> = fn(&Request, &Params, &S) -> HandlerResult;
+
+/// Path parameters captured by `{name}` segments of the matched route.
+pub struct Params {
+    values: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    Missing(&'static str),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Missing(name) => write!(f, "missing path parameter `{}`", name),
+            ParamError::Invalid(name) => write!(f, "invalid path parameter `{}`", name),
+        }
+    }
+}
+
+impl Params {
+    pub fn get<T: FromStr>(&self, name: &'static str) -> Result<T, ParamError> {
+        let (_, value) = self
+            .values
+            .iter()
+            .find(|(key, _)| *key == name)
+            .ok_or(ParamError::Missing(name))?;
+        value.parse().map_err(|_| ParamError::Invalid(name))
+    }
+}
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+struct Route<S> {
+    method: &'static str,
+    segments: Vec<Segment>,
+    handler: Handler<S>,
+}
+
+impl<S> Route<S> {
+    fn matches(&self, path_segments: &[String]) -> Option<Params> {
+        if self.segments.len() != path_segments.len() {
+            return None;
+        }
+        let mut values = Vec::new();
+        for (segment, actual) in self.segments.iter().zip(path_segments) {
+            match segment {
+                Segment::Literal(literal) if literal == actual => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => values.push((*name, actual.clone())),
+            }
+        }
+        Some(Params { values })
+    }
+
+    fn literal_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count()
+    }
+}
+
+/// Dispatches requests by method and path pattern, e.g. `GET /users/{id}`.
+///
+/// When several patterns match a path, only those with the most literal
+/// segments count, so `/users/search` shadows `/users/{id}` for every method
+/// and the `Allow` header lists only what `/users/search` accepts. HEAD is
+/// answered by any GET route (the body is dropped when the response is
+/// written), OPTIONS by every path, and a path that exists under other methods
+/// yields 405 with an `Allow` header.
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Router<S> {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route(mut self, method: &'static str, pattern: &'static str, handler: Handler<S>) -> Router<S> {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name),
+                None => Segment::Literal(segment),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            segments,
+            handler,
+        });
+        self
+    }
+
+    pub fn dispatch(&self, request: &Request, state: &S) -> Response {
+        self.route_request(request, state)
+            .unwrap_or_else(|e| e.to_response())
+    }
+
+    fn route_request(&self, request: &Request, state: &S) -> HandlerResult {
+        let path_segments: Option<Vec<String>> = split_path(&request.path)
+            .into_iter()
+            .map(percent_decode)
+            .collect();
+        let Some(path_segments) = path_segments else {
+            return Err(AppError::RouteNotFound);
+        };
+
+        let matched: Vec<(&Route<S>, Params)> = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, route.matches(&path_segments)?)))
+            .collect();
+        let specificity = matched.iter().map(|(route, _)| route.literal_count()).max();
+
+        let mut allowed: Vec<&'static str> = Vec::new();
+        for (route, params) in &matched {
+            if Some(route.literal_count()) != specificity {
+                continue;
+            }
+            if route.method == request.method || (route.method == "GET" && request.method == "HEAD") {
+                return (route.handler)(request, params, state);
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if allowed.is_empty() {
+            return Err(AppError::RouteNotFound);
+        }
+        if allowed.contains(&"GET") {
+            allowed.push("HEAD");
+        }
+        allowed.push("OPTIONS");
+        let allow = allowed.join(", ");
+
+        if request.method == "OPTIONS" {
+            return Ok(Response::new(204).header("Allow", allow));
+        }
+        Err(AppError::MethodNotAllowed { allow })
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    match path.strip_prefix('/') {
+        Some("") | None => Vec::new(),
+        Some(rest) => rest.split('/').collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_: &Request, _: &Params, _: &()) -> HandlerResult {
+        Ok(Response::new(200))
+    }
+
+    fn id(_: &Request, params: &Params, _: &()) -> HandlerResult {
+        let id: i32 = params.get("id").map_err(|_| AppError::RouteNotFound)?;
+        Ok(Response::new(200).header("X-Id", id.to_string()))
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .route("GET", "/users/search", ok)
+            .route("POST", "/users/bulk", ok)
+            .route("GET", "/users/{id}", id)
+            .route("PUT", "/users/{id}", id)
+            .route("DELETE", "/users/{id}", id)
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            version: "HTTP/1.1".to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+            id: "test".to_string(),
+        }
+    }
+
+    fn allow(response: &Response) -> Option<&str> {
+        response.header_value("Allow")
+    }
+
+    #[test]
+    fn options_on_literal_route_lists_only_its_methods() {
+        let response = router().dispatch(&request("OPTIONS", "/users/search"), &());
+        assert_eq!(response.status, 204);
+        assert_eq!(allow(&response), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn other_methods_on_literal_route_are_not_allowed() {
+        for method in ["GET", "HEAD", "PUT", "DELETE"] {
+            let response = router().dispatch(&request(method, "/users/bulk"), &());
+            assert_eq!(response.status, 405, "{}", method);
+            assert_eq!(allow(&response), Some("POST, OPTIONS"), "{}", method);
+        }
+    }
+
+    #[test]
+    fn parameterised_route_still_matches_other_paths() {
+        let response = router().dispatch(&request("GET", "/users/5"), &());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header_value("X-Id"), Some("5"));
+
+        let response = router().dispatch(&request("OPTIONS", "/users/5"), &());
+        assert_eq!(allow(&response), Some("GET, PUT, DELETE, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn unknown_path_is_not_found() {
+        let response = router().dispatch(&request("GET", "/nothing"), &());
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,543 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use crate::bulk::DEFAULT_MAX_BULK_OPERATIONS;
+use crate::config::Config;
+use crate::connection_pool::ConnectionPool;
+use crate::error::AppError;
+use crate::handlers::user_routes;
+use crate::logging::{self, LogLevel};
+use crate::purge::{spawn_purger, RetentionConfig};
+use crate::repository::{InMemoryUserRepository, UserRepository};
+use crate::request::{read_request, Deadline, Request, RequestError, RequestLimits};
+use crate::response::Response;
+use crate::router::{Handler, Router};
+use crate::state::AppState;
+use crate::thread_pool::ThreadPool;
+use crate::utils::panic_message;
+
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+pub const DEFAULT_WORKER_THREADS: usize = 8;
+pub const DEFAULT_WORKER_QUEUE_SIZE: usize = 64;
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// How long a new connection may stay silent before its first request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a response may wait for a client that does not read it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often an idle keep-alive connection checks whether the server is draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The rest of the chain, ending in the router.
+pub type Next<'a> = &'a dyn Fn(&Request) -> Response;
+
+/// Wraps every request: it may inspect or answer the request itself, or call
+/// `next` and adjust the response.
+pub type Middleware = Box<dyn Fn(&Request, Next) -> Response + Send + Sync>;
+
+/// Configures a [`Server`].
+///
+/// ```no_run
+/// use rust_crud_api::{InMemoryUserRepository, Server};
+///
+/// let server = Server::builder()
+///     .bind("127.0.0.1:0")
+///     .repository(Box::new(InMemoryUserRepository::new()))
+///     .build()
+///     .unwrap();
+/// println!("listening on {}", server.local_addr().unwrap());
+/// server.run();
+/// ```
+pub struct ServerBuilder {
+    address: String,
+    users: Option<Box<dyn UserRepository>>,
+    pool: Option<Arc<ConnectionPool>>,
+    require_if_match: bool,
+    max_bulk_operations: usize,
+    limits: RequestLimits,
+    workers: usize,
+    queue_size: usize,
+    retention: Option<RetentionConfig>,
+    shutdown_grace: Duration,
+    routes: Vec<(&'static str, &'static str, Handler<AppState>)>,
+    middleware: Vec<Middleware>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            address: DEFAULT_ADDRESS.to_string(),
+            users: None,
+            pool: None,
+            require_if_match: false,
+            max_bulk_operations: DEFAULT_MAX_BULK_OPERATIONS,
+            limits: RequestLimits::default(),
+            workers: DEFAULT_WORKER_THREADS,
+            queue_size: DEFAULT_WORKER_QUEUE_SIZE,
+            retention: None,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Takes the `server` and `purge` settings, including the background purge,
+    /// from `config`. The repository is left to the caller.
+    pub fn from_config(config: &Config) -> ServerBuilder {
+        ServerBuilder {
+            address: config.server.bind.clone(),
+            require_if_match: config.server.require_if_match,
+            max_bulk_operations: config.server.bulk_max_operations,
+            limits: config.request_limits(),
+            workers: config.server.workers,
+            queue_size: config.server.queue_size,
+            retention: Some(config.retention()),
+            shutdown_grace: Duration::from_secs(config.server.shutdown_grace_secs),
+            ..ServerBuilder::default()
+        }
+    }
+
+    /// The address to listen on; port 0 picks a free one.
+    pub fn bind(mut self, address: impl Into<String>) -> ServerBuilder {
+        self.address = address.into();
+        self
+    }
+
+    /// Where users are stored; in memory unless set.
+    pub fn repository(mut self, users: Box<dyn UserRepository>) -> ServerBuilder {
+        self.users = Some(users);
+        self
+    }
+
+    /// The Postgres pool behind the repository, reported at `GET /metrics/pool`.
+    pub fn pool(mut self, pool: Arc<ConnectionPool>) -> ServerBuilder {
+        self.pool = Some(pool);
+        self
+    }
+
+    pub fn require_if_match(mut self, require: bool) -> ServerBuilder {
+        self.require_if_match = require;
+        self
+    }
+
+    pub fn max_bulk_operations(mut self, max: usize) -> ServerBuilder {
+        self.max_bulk_operations = max;
+        self
+    }
+
+    pub fn limits(mut self, limits: RequestLimits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.workers = workers;
+        self
+    }
+
+    pub fn queue_size(mut self, queue_size: usize) -> ServerBuilder {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Purges deleted users in the background; off unless set.
+    pub fn purge(mut self, retention: RetentionConfig) -> ServerBuilder {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// How long a shutdown waits for in-flight requests before giving up on them.
+    pub fn shutdown_grace(mut self, grace: Duration) -> ServerBuilder {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Adds a route of your own. These are tried before the user API's, so
+    /// they can add paths under `/users` or replace an endpoint.
+    pub fn route(mut self, method: &'static str, pattern: &'static str, handler: Handler<AppState>) -> ServerBuilder {
+        self.routes.push((method, pattern, handler));
+        self
+    }
+
+    /// Adds a middleware. The first one added sees the request first and the
+    /// response last.
+    pub fn middleware(
+        mut self,
+        middleware: impl Fn(&Request, Next) -> Response + Send + Sync + 'static,
+    ) -> ServerBuilder {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Binds the listener, so the address is known before the server runs.
+    pub fn build(self) -> io::Result<Server> {
+        let address = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address resolves to nothing"))?;
+        let listener = TcpListener::bind(address)?;
+        let wake_address = local_connect_address(listener.local_addr()?);
+
+        let router = self
+            .routes
+            .into_iter()
+            .fold(Router::new(), |router, (method, pattern, handler)| router.route(method, pattern, handler));
+        let requested = Arc::new(AtomicBool::new(false));
+        let state = AppState {
+            users: self.users.unwrap_or_else(|| Box::new(InMemoryUserRepository::new())),
+            pool: self.pool,
+            require_if_match: self.require_if_match,
+            max_bulk_operations: self.max_bulk_operations.max(1),
+            draining: Arc::clone(&requested),
+        };
+        Ok(Server {
+            listener,
+            state: Arc::new(state),
+            router: user_routes(router),
+            middleware: self.middleware,
+            limits: self.limits,
+            workers: self.workers.max(1),
+            queue_size: self.queue_size,
+            retention: self.retention,
+            shutdown_grace: self.shutdown_grace,
+            shutdown: ShutdownHandle {
+                requested,
+                wake_address,
+            },
+        })
+    }
+}
+
+/// Asks a running [`Server`] to shut down. Clones share the server; use one
+/// from a signal handler or a test.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    /// Where to connect to wake the accept loop.
+    wake_address: SocketAddr,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and starts draining. Returns at once;
+    /// [`Server::run`] returns when the draining is over.
+    pub fn shutdown(&self) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            // Whatever the outcome, the next accepted connection ends the loop.
+            let _ = TcpStream::connect_timeout(&self.wake_address, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// How [`Server::run`] ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every accepted connection was served and closed.
+    Drained,
+    /// The grace period ran out while this many connections were still being
+    /// served; they were left behind.
+    TimedOut { in_flight: usize },
+}
+
+/// The user API bound to a listening socket; see [`ServerBuilder`].
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<AppState>,
+    router: Router<AppState>,
+    middleware: Vec<Middleware>,
+    limits: RequestLimits,
+    workers: usize,
+    queue_size: usize,
+    retention: Option<RetentionConfig>,
+    shutdown_grace: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Shared with the handlers, e.g. to seed the repository before running.
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves until asked to shut down through a [`ShutdownHandle`], then stops
+    /// accepting, lets in-flight requests finish within the grace period, and
+    /// closes the connection pool.
+    pub fn run(self) -> Shutdown {
+        let Server {
+            listener,
+            state,
+            router,
+            middleware,
+            limits,
+            workers,
+            queue_size,
+            retention,
+            shutdown_grace,
+            shutdown,
+        } = self;
+        let pool = state.pool.clone();
+        let (stop_purger, purger_stop) = mpsc::channel();
+        let purger = retention.and_then(|retention| spawn_purger(Arc::clone(&state), retention, purger_stop));
+        match listener.local_addr() {
+            Ok(address) if logging::enabled(LogLevel::Info) => {
+                println!("Server listening on {} with {} workers", address, workers)
+            }
+            Ok(_) => {}
+            Err(e) => println!("Error: {}", e),
+        }
+
+        let draining = Arc::clone(&shutdown.requested);
+        let worker_pool = ThreadPool::new(workers, queue_size, move |stream| {
+            let endpoint = |request: &Request| router.dispatch(request, &state);
+            let service = |request: &Request| call_chain(&middleware, request, &endpoint);
+            handle_client(stream, &service, &limits, &draining)
+        });
+
+        for stream in listener.incoming() {
+            if shutdown.is_requested() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(stream) = worker_pool.try_execute(stream) {
+                        reject_client(stream);
+                    }
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                }
+            }
+        }
+
+        // New connections are refused from here on.
+        drop(listener);
+        drop(stop_purger);
+        if logging::enabled(LogLevel::Info) {
+            println!("Shutting down; draining connections for up to {}s", shutdown_grace.as_secs());
+        }
+        let in_flight = worker_pool.shutdown(shutdown_grace);
+        // A purge already running finishes before the pool closes under it.
+        if let Some(purger) = purger {
+            let _ = purger.join();
+        }
+        if let Some(pool) = pool {
+            pool.close();
+        }
+        if in_flight > 0 {
+            return Shutdown::TimedOut { in_flight };
+        }
+        if logging::enabled(LogLevel::Info) {
+            println!("Shutdown complete");
+        }
+        Shutdown::Drained
+    }
+}
+
+/// Where a client on this machine reaches a server bound to `address`: on
+/// loopback if it listens on every interface.
+pub fn local_connect_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    address
+}
+
+fn call_chain(middleware: &[Middleware], request: &Request, endpoint: Next) -> Response {
+    match middleware.split_first() {
+        Some((first, rest)) => first(request, &|request| call_chain(rest, request, endpoint)),
+        None => endpoint(request),
+    }
+}
+
+/// Serves requests from one connection until either side closes it. HTTP/1.1
+/// connections are kept open between requests, including pipelined ones, for
+/// as long as the client sends another within the keep-alive timeout.
+fn handle_client(stream: TcpStream, service: Next, limits: &RequestLimits, draining: &AtomicBool) {
+    // A client that stops reading must not pin a worker either.
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        println!("Error: {}", e);
+        return;
+    }
+    let mut reader = BufReader::new(Connection { stream, deadline: None });
+    // Until its first request starts the connection is as idle as a kept-alive
+    // one, so a drain closes it too.
+    if !wait_for_request(&mut reader, READ_TIMEOUT, draining) {
+        return;
+    }
+
+    loop {
+        let (response, head_only, keep_alive) = match read_request(&mut reader, limits) {
+            Ok(request) => {
+                let started = Instant::now();
+                let response = dispatch_catching_panics(service, &request)
+                    .header("X-Request-Id", request.id.as_str());
+                if logging::enabled(LogLevel::Debug) {
+                    let elapsed = started.elapsed().as_millis();
+                    println!("{} {} {} {}ms {}", request.method, request.path, response.status, elapsed, request.id);
+                }
+                let keep_alive = limits.keep_alive_timeout.is_some()
+                    && request.keep_alive()
+                    && !response.closes_connection()
+                    && !draining.load(Ordering::SeqCst);
+                let response = match (keep_alive, request.version.as_str()) {
+                    (false, _) if !response.closes_connection() => response.header("Connection", "close"),
+                    (true, "HTTP/1.0") => response.header("Connection", "keep-alive"),
+                    _ => response,
+                };
+                (response, request.method == "HEAD", keep_alive)
+            }
+            Err(e) => {
+                let error = match e {
+                    RequestError::ConnectionClosed => return,
+                    RequestError::Io(e) => {
+                        println!("Error: {}", e);
+                        return;
+                    }
+                    RequestError::Malformed(reason) => AppError::BadRequest(reason.to_string()),
+                    RequestError::TimedOut => AppError::RequestTimeout,
+                    RequestError::HeadersTooLarge => AppError::RequestHeaderFieldsTooLarge,
+                    RequestError::BodyTooLarge => AppError::PayloadTooLarge,
+                    RequestError::UnsupportedTransferEncoding => {
+                        AppError::NotImplemented("unsupported Transfer-Encoding".to_string())
+                    }
+                };
+                // The rest of the stream cannot be trusted, so these all close it.
+                (error.to_response(), false, false)
+            }
+        };
+
+        // The client may already be gone; that is its problem, not the server's.
+        if let Err(e) = response.write_to(reader.get_mut(), head_only) {
+            println!("Error writing response: {}", e);
+            return;
+        }
+        match limits.keep_alive_timeout {
+            Some(timeout) if keep_alive && wait_for_request(&mut reader, timeout, draining) => {}
+            _ => return,
+        }
+    }
+}
+
+/// Waits up to `timeout` for the client to start its next request; false if
+/// it closed the connection, stayed silent, or the server started draining.
+fn wait_for_request(reader: &mut BufReader<Connection>, timeout: Duration, draining: &AtomicBool) -> bool {
+    // Pipelined requests are already buffered.
+    if !reader.buffer().is_empty() {
+        return true;
+    }
+    // `None` for a timeout too long to represent; keep polling until the client or a drain ends the wait.
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        if draining.load(Ordering::SeqCst) {
+            return request_arrived(reader);
+        }
+        let remaining = deadline.map_or(DRAIN_POLL_INTERVAL, |at| at.saturating_duration_since(Instant::now()));
+        if remaining.is_zero() {
+            return false;
+        }
+        if reader.get_ref().stream.set_read_timeout(Some(remaining.min(DRAIN_POLL_INTERVAL))).is_err() {
+            return false;
+        }
+        match reader.fill_buf() {
+            Ok(buffer) => return !buffer.is_empty(),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Whether the client has already sent something, without waiting for it. A
+/// drain closes only connections where nothing has arrived, so a request that
+/// was sent is answered rather than silently dropped.
+fn request_arrived(reader: &mut BufReader<Connection>) -> bool {
+    if reader.get_ref().stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let arrived = matches!(reader.fill_buf(), Ok(buffer) if !buffer.is_empty());
+    reader.get_ref().stream.set_nonblocking(false).is_ok() && arrived
+}
+
+/// An accepted socket. While `read_request` has set a deadline, every read
+/// blocks for at most what is left of it.
+struct Connection {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Deadline for Connection {
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Runs the handler for `request`, turning a panic into a 500 so that a bug in one
+/// handler only fails the request that triggered it.
+fn dispatch_catching_panics(service: Next, request: &Request) -> Response {
+    match panic::catch_unwind(AssertUnwindSafe(|| service(request))) {
+        Ok(response) => response,
+        Err(payload) => {
+            println!(
+                "Error: handler for {} {} panicked: {}",
+                request.method,
+                request.path,
+                panic_message(payload.as_ref())
+            );
+            AppError::Internal.to_response()
+        }
+    }
+}
+
+fn reject_client(mut stream: TcpStream) {
+    // Called from the accept loop, so never let a slow client stall it.
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    if let Err(e) = AppError::ServerBusy.to_response().write_to(&mut stream, false) {
+        println!("Error: {}", e);
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use crate::connection_pool::ConnectionPool;
+use crate::repository::UserRepository;
+
+/// Everything a request handler needs, shared by all workers.
+pub struct AppState {
+    pub users: Box<dyn UserRepository>,
+    /// Present only when users are stored in Postgres.
+    pub pool: Option<Arc<ConnectionPool>>,
+    /// Reject writes without `If-Match` with 428 instead of applying them blindly.
+    pub require_if_match: bool,
+    /// Most operations accepted in one `POST /users/bulk`.
+    pub max_bulk_operations: usize,
+    /// Set once the server has started shutting down.
+    pub draining: Arc<AtomicBool>,
+}
@@ -0,0 +1,103 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::utils::panic_message;
+
+type Handler<T> = Arc<dyn Fn(T) + Send + Sync + 'static>;
+
+/// A fixed set of worker threads fed through a bounded queue.
+///
+/// Work is handed over with [`ThreadPool::try_execute`], which never blocks: once
+/// every worker is busy and the queue is full the item is given back to the caller.
+pub struct ThreadPool<T: Send + 'static> {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<T>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    pub fn new<F>(size: usize, queue_limit: usize, handler: F) -> ThreadPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_limit);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler: Handler<T> = Arc::new(handler);
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || worker_loop(&receiver, &handler))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    /// Queues `item` for a worker, or returns it when the pool is saturated.
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        match self.sender.as_ref() {
+            Some(sender) => sender.try_send(item).map_err(|e| match e {
+                TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+            }),
+            None => Err(item),
+        }
+    }
+
+    /// Stops taking work and waits up to `timeout` for the workers to finish
+    /// what they were given. Returns how many were still busy when it gave up;
+    /// those are left running, detached.
+    pub fn shutdown(mut self, timeout: Duration) -> usize {
+        drop(self.sender.take());
+        // A timeout too long to represent never runs out.
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let busy = self.workers.iter().filter(|worker| !worker.is_finished()).count();
+            if busy == 0 || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                // Dropping the handles detaches them, so `drop` does not wait either.
+                self.workers.clear();
+                return busy;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+fn worker_loop<T>(receiver: &Mutex<Receiver<T>>, handler: &Handler<T>) {
+    loop {
+        let next = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match next {
+            Ok(item) => {
+                // Keep the worker alive whatever the handler does; a panicking
+                // worker would otherwise shrink the pool for good.
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(item))) {
+                    println!("Error: worker caught a panic: {}", panic_message(payload.as_ref()));
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for ThreadPool<T> {
+    fn drop(&mut self) {
+        // Closing the channel lets every worker finish its queue and exit.
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+use crate::error::AppError;
+use crate::models::NewUser;
+
+pub const MAX_NAME_LENGTH: usize = 100;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_EMAIL_LOCAL_PART_LENGTH: usize = 64;
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> FieldError {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Parses and validates the body of a create or full update request.
+///
+/// Every problem is collected rather than stopping at the first, so a client
+/// can fix all fields in one round trip.
+pub fn parse_new_user(body: &[u8]) -> Result<NewUser, AppError> {
+    let value: Value = serde_json::from_slice(body)?;
+    let Value::Object(object) = value else {
+        return Err(AppError::Validation(vec![FieldError::new("", "body must be a JSON object")]));
+    };
+    validate_user_object(&object)
+}
+
+pub fn validate_user_object(object: &Map<String, Value>) -> Result<NewUser, AppError> {
+    let mut errors = Vec::new();
+
+    for key in object.keys() {
+        match key.as_str() {
+            "name" | "email" => {}
+            "id" => errors.push(FieldError::new("id", "must not be supplied, ids are assigned by the server")),
+            _ => errors.push(FieldError::new(key, "unknown field")),
+        }
+    }
+
+    let name = required_string(object, "name", &mut errors).and_then(|name| {
+        validate_name(&name).map_err(|e| errors.push(e)).ok()
+    });
+    let email = required_string(object, "email", &mut errors).and_then(|email| {
+        validate_email(&email).map_err(|e| errors.push(e)).ok()
+    });
+
+    match (name, email) {
+        (Some(name), Some(email)) if errors.is_empty() => Ok(NewUser { name, email }),
+        _ => Err(AppError::Validation(errors)),
+    }
+}
+
+fn required_string(object: &Map<String, Value>, field: &str, errors: &mut Vec<FieldError>) -> Option<String> {
+    match object.get(field) {
+        Some(Value::String(value)) => Some(value.trim().to_string()),
+        Some(_) => {
+            errors.push(FieldError::new(field, "must be a string"));
+            None
+        }
+        None => {
+            errors.push(FieldError::new(field, "is required"));
+            None
+        }
+    }
+}
+
+/// Expects an already trimmed name.
+pub fn validate_name(name: &str) -> Result<String, FieldError> {
+    if name.is_empty() {
+        return Err(FieldError::new("name", "must not be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(FieldError::new("name", format!("must be at most {} characters", MAX_NAME_LENGTH)));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(FieldError::new("name", "must not contain control characters"));
+    }
+    Ok(name.to_string())
+}
+
+/// Expects an already trimmed address. This is a pragmatic subset of RFC 5321:
+/// dot-atom local parts and hostname-style domains, which covers real mailboxes.
+pub fn validate_email(email: &str) -> Result<String, FieldError> {
+    let invalid = || FieldError::new("email", "must be a valid email address");
+
+    if email.is_empty() {
+        return Err(FieldError::new("email", "must not be empty"));
+    }
+    if email.chars().count() > MAX_EMAIL_LENGTH {
+        return Err(FieldError::new("email", format!("must be at most {} characters", MAX_EMAIL_LENGTH)));
+    }
+
+    let (local, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+    let local_ok = !local.is_empty()
+        && local.len() <= MAX_EMAIL_LOCAL_PART_LENGTH
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c))
+        });
+    let domain_ok = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if local_ok && domain_ok {
+        Ok(email.to_string())
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fields `body` is rejected for, in the order they were reported.
+    fn rejected(body: &str) -> Vec<String> {
+        match parse_new_user(body.as_bytes()) {
+            Err(AppError::Validation(errors)) => errors.into_iter().map(|e| e.field).collect(),
+            Err(e) => panic!("expected a validation error, got {}", e),
+            Ok(_) => panic!("expected {} to be rejected", body),
+        }
+    }
+
+    #[test]
+    fn trims_name_and_email() {
+        let user = parse_new_user(br#"{"name":"  Ann Lee \t","email":" ann@example.com\n"}"#).unwrap();
+        assert_eq!(user.name, "Ann Lee");
+        assert_eq!(user.email, "ann@example.com");
+        assert_eq!(rejected(r#"{"name":"   ","email":"ann@example.com"}"#), ["name"]);
+    }
+
+    #[test]
+    fn limits_lengths() {
+        let name = "é".repeat(MAX_NAME_LENGTH);
+        assert!(validate_name(&name).is_ok());
+        assert!(validate_name(&format!("{}é", name)).is_err());
+
+        let domain = format!("{}.com", vec!["a".repeat(63); 3].join("."));
+        let local = "l".repeat(MAX_EMAIL_LOCAL_PART_LENGTH);
+        let longest = format!("{}@{}", local, &domain[domain.len() - (MAX_EMAIL_LENGTH - local.len() - 1)..]);
+        assert_eq!(longest.len(), MAX_EMAIL_LENGTH);
+        assert!(validate_email(&longest).is_ok());
+        assert!(validate_email(&format!("x{}", longest)).is_err());
+        assert!(validate_email(&format!("{}l@example.com", local)).is_err());
+        assert!(validate_email(&format!("ann@{}.com", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn checks_email_format() {
+        let valid = ["ann@example.com", "ann.lee+tag@mail.example.co.uk", "o'neil@x-y.org", "a@b.c"];
+        for email in valid {
+            assert!(validate_email(email).is_ok(), "{}", email);
+        }
+        let invalid = [
+            "",
+            "ann",
+            "ann@",
+            "@example.com",
+            "ann@localhost",
+            "ann@@example.com",
+            ".ann@example.com",
+            "ann..lee@example.com",
+            "ann lee@example.com",
+            "ann@-example.com",
+            "ann@example-.com",
+            "ann@example..com",
+            "ann@exa_mple.com",
+            "\"ann\"@example.com",
+        ];
+        for email in invalid {
+            assert!(validate_email(email).is_err(), "{}", email);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_ids() {
+        let fields = rejected(r#"{"id":7,"name":"Ann","email":"ann@example.com","role":"admin"}"#);
+        assert_eq!(fields, ["id", "role"]);
+        match parse_new_user(br#"{"id":7,"name":"Ann","email":"ann@example.com"}"#) {
+            Err(AppError::Validation(errors)) => assert!(errors[0].message.contains("assigned by the server")),
+            _ => panic!("expected the id to be rejected"),
+        }
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        assert_eq!(rejected(r#"{"extra":true,"name":42}"#), ["extra", "name", "email"]);
+        assert_eq!(rejected(r#"{"name":"Ann\u0007","email":"not an email"}"#), ["name", "email"]);
+        assert_eq!(rejected("[]"), [""]);
+        assert!(matches!(parse_new_user(b"{"), Err(AppError::MalformedJson(_))));
+    }
+}
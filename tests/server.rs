@@ -0,0 +1,836 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use rust_crud_api::{
+    AppState, HandlerResult, InMemoryUserRepository, NewUser, Params, Request, RequestLimits, Response,
+    RepositoryError, Server, ServerBuilder, Shutdown, ShutdownHandle, User, UserRepository,
+};
+use rust_crud_api::audit::{AuditContext, AuditEntry, AuditQuery};
+use rust_crud_api::bulk::BulkOperation;
+use rust_crud_api::list_query::{ListQuery, Page};
+use rust_crud_api::models::SearchHit;
+use rust_crud_api::repository::BulkResult;
+use rust_crud_api::purge::{purge_expired, RetentionConfig};
+use serde_json::Value;
+
+struct Running {
+    address: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<Shutdown>,
+}
+
+impl Running {
+    fn connect(&self) -> Client {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Client {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    fn stop(self) -> Shutdown {
+        self.shutdown.shutdown();
+        self.thread.join().unwrap()
+    }
+}
+
+fn start(builder: ServerBuilder) -> Running {
+    start_with_state(builder).0
+}
+
+fn start_with_state(builder: ServerBuilder) -> (Running, Arc<AppState>) {
+    start_with_repository(builder, Box::new(InMemoryUserRepository::new()))
+}
+
+fn start_with_repository(builder: ServerBuilder, repository: Box<dyn UserRepository>) -> (Running, Arc<AppState>) {
+    let server = builder.bind("127.0.0.1:0").repository(repository).build().unwrap();
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let state = Arc::clone(server.state());
+    let thread = thread::spawn(move || server.run());
+    let running = Running {
+        address,
+        shutdown,
+        thread,
+    };
+    (running, state)
+}
+
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn send(&mut self, raw: &str) {
+        self.reader.get_mut().write_all(raw.as_bytes()).unwrap();
+    }
+
+    fn request(&mut self, method: &str, path: &str, body: Option<&str>) -> Reply {
+        self.request_with(method, path, &[], body)
+    }
+
+    fn request_with(&mut self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&str>) -> Reply {
+        let body = body.unwrap_or_default();
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        self.send(&format!(
+            "{} {} HTTP/1.1\r\nHost: test\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        ));
+        self.read_reply(method == "HEAD")
+    }
+
+    /// Creates a user and returns its id.
+    fn create(&mut self, name: &str, email: &str) -> i64 {
+        let body = serde_json::json!({ "name": name, "email": email }).to_string();
+        assert_eq!(self.request("POST", "/users", Some(&body)).status, 200);
+        let found = self.request("GET", &format!("/users?email={}", email), None).json();
+        found[0]["id"].as_i64().unwrap()
+    }
+
+    /// Reads one response, relying on `Content-Length` as the server always sends it.
+    fn read_reply(&mut self, head_only: bool) -> Reply {
+        let mut status_line = String::new();
+        self.reader.read_line(&mut status_line).unwrap();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+        let mut reply = Reply {
+            status,
+            headers,
+            body: String::new(),
+        };
+        let length: usize = reply.header("Content-Length").map_or(0, |value| value.parse().unwrap());
+        if !head_only && length > 0 {
+            let mut body = vec![0; length];
+            self.reader.read_exact(&mut body).unwrap();
+            reply.body = String::from_utf8(body).unwrap();
+        }
+        reply
+    }
+
+    fn is_closed(&mut self) -> bool {
+        matches!(self.reader.read(&mut [0; 1]), Ok(0))
+    }
+}
+
+#[test]
+fn crud() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+
+    let created = client.request("POST", "/users", Some(r#"{"name":"Ann","email":"ann@example.com"}"#));
+    assert_eq!(created.status, 200);
+    let listed = client.request("GET", "/users", None);
+    assert_eq!(listed.status, 200);
+    let id = listed.json()[0]["id"].as_i64().unwrap();
+
+    let fetched = client.request("GET", &format!("/users/{}", id), None);
+    assert_eq!(fetched.status, 200);
+    assert_eq!(fetched.json()["email"], "ann@example.com");
+    let etag = fetched.header("ETag").unwrap().to_string();
+
+    let renamed = r#"{"name":"Annie","email":"ann@example.com"}"#;
+    let updated = client.request("PUT", &format!("/users/{}", id), Some(renamed));
+    assert_eq!(updated.status, 200);
+    assert_ne!(updated.header("ETag"), Some(etag.as_str()));
+    let fetched = client.request("GET", &format!("/users/{}", id), None);
+    assert_eq!(fetched.json()["name"], "Annie");
+
+    let head = client.request("HEAD", &format!("/users/{}", id), None);
+    assert_eq!(head.status, 200);
+    assert!(head.body.is_empty());
+
+    assert_eq!(client.request("DELETE", &format!("/users/{}", id), None).status, 200);
+    assert_eq!(client.request("GET", &format!("/users/{}", id), None).status, 404);
+    assert_eq!(client.request("GET", "/users/999", None).status, 404);
+    assert_eq!(client.request("GET", "/users/abc", None).status, 400);
+
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn emails_are_unique_regardless_of_case() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+    client.create("Ann", "ann@example.com");
+    let bob = client.create("Bob", "bob@example.com");
+
+    let created = client.request("POST", "/users", Some(r#"{"name":"Ann","email":"ANN@Example.com"}"#));
+    assert_eq!(created.status, 409);
+    assert_eq!(created.json()["field"], "email");
+    let taken = r#"{"name":"Bob","email":"Ann@example.COM"}"#;
+    let updated = client.request("PUT", &format!("/users/{}", bob), Some(taken));
+    assert_eq!(updated.status, 409);
+    assert_eq!(updated.json()["field"], "email");
+
+    // Changing the case of one's own email is not a conflict.
+    let recased = r#"{"name":"Bob","email":"Bob@Example.com"}"#;
+    assert_eq!(client.request("PUT", &format!("/users/{}", bob), Some(recased)).status, 200);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn literal_routes_answer_options_and_405_for_themselves() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+
+    let options = client.request("OPTIONS", "/users/search", None);
+    assert_eq!(options.status, 204);
+    assert_eq!(options.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+    for method in ["GET", "PUT", "DELETE"] {
+        let reply = client.request(method, "/users/bulk", None);
+        assert_eq!(reply.status, 405, "{}", method);
+        assert_eq!(reply.header("Allow"), Some("POST, OPTIONS"), "{}", method);
+    }
+
+    let options = client.request("OPTIONS", "/users/1", None);
+    assert_eq!(options.status, 204);
+    assert!(options.header("Allow").unwrap().contains("DELETE"));
+    assert_eq!(client.request("GET", "/nothing", None).status, 404);
+
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn keep_alive() {
+    let server = start(Server::builder());
+
+    let mut client = server.connect();
+    for _ in 0..3 {
+        let reply = client.request("GET", "/users", None);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("Connection"), None);
+    }
+
+    client.send("GET /users HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(client.read_reply(false).header("Connection"), Some("close"));
+    assert!(client.is_closed());
+
+    let mut client = server.connect();
+    client.send("GET /users HTTP/1.0\r\n\r\n");
+    assert_eq!(client.read_reply(false).header("Connection"), Some("close"));
+    assert!(client.is_closed());
+
+    let mut client = server.connect();
+    client.send("GET /users HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    assert_eq!(client.read_reply(false).header("Connection"), Some("keep-alive"));
+    assert_eq!(client.request("GET", "/users", None).status, 200);
+
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let limits = RequestLimits {
+        keep_alive_timeout: Some(Duration::from_millis(200)),
+        ..RequestLimits::default()
+    };
+    let server = start(Server::builder().limits(limits));
+    let mut client = server.connect();
+    assert_eq!(client.request("GET", "/users", None).status, 200);
+    let started = Instant::now();
+    assert!(client.is_closed());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn trickling_request_times_out() {
+    let limits = RequestLimits {
+        request_timeout: Duration::from_millis(300),
+        ..RequestLimits::default()
+    };
+    let server = start(Server::builder().limits(limits));
+    let mut client = server.connect();
+    let started = Instant::now();
+    for byte in "GET /users HTTP/1.1\r\n".chars() {
+        // The peer may already have answered and closed.
+        if client.reader.get_mut().write_all(&[byte as u8]).is_err() || started.elapsed() > Duration::from_secs(1) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let reply = client.read_reply(false);
+    assert_eq!(reply.status, 408);
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn pipelining() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+
+    client.send(concat!(
+        "POST /users HTTP/1.1\r\nContent-Length: 40\r\n\r\n{\"name\":\"Ann\",\"email\":\"ann@example.com\"}",
+        "GET /users/1 HTTP/1.1\r\n\r\n",
+        "HEAD /users/1 HTTP/1.1\r\n\r\n",
+        "DELETE /users/bulk HTTP/1.1\r\n\r\n",
+        "GET /users/1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    ));
+    assert_eq!(client.read_reply(false).status, 200);
+    let fetched = client.read_reply(false);
+    assert_eq!(fetched.json()["name"], "Ann");
+    let head = client.read_reply(true);
+    assert_eq!(head.header("Content-Length").unwrap(), fetched.body.len().to_string());
+    assert_eq!(client.read_reply(false).status, 405);
+    let last = client.read_reply(false);
+    assert_eq!(last.status, 200);
+    assert_eq!(last.header("Connection"), Some("close"));
+    assert!(client.is_closed());
+
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn middleware_wraps_requests() {
+    let server = start(Server::builder().middleware(|request, next| {
+        if request.header("X-Block").is_some() {
+            return Response::text(403, "blocked");
+        }
+        next(request).header("X-Wrapped", "yes")
+    }));
+    let mut client = server.connect();
+    assert_eq!(client.request("GET", "/users", None).header("X-Wrapped"), Some("yes"));
+    client.send("GET /users HTTP/1.1\r\nX-Block: 1\r\n\r\n");
+    assert_eq!(client.read_reply(false).status, 403);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn shutdown_closes_idle_and_silent_connections() {
+    let server = start(Server::builder());
+    let mut idle = server.connect();
+    assert_eq!(idle.request("GET", "/users", None).status, 200);
+    let mut silent = server.connect();
+    let address = server.address;
+
+    let started = Instant::now();
+    assert_eq!(server.stop(), Shutdown::Drained);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(idle.is_closed());
+    assert!(silent.is_closed());
+    assert!(TcpStream::connect(address).is_err());
+}
+
+fn slow(_request: &Request, _params: &Params, _state: &AppState) -> HandlerResult {
+    thread::sleep(Duration::from_millis(500));
+    Ok(Response::text(200, "done"))
+}
+
+#[test]
+fn shutdown_lets_in_flight_requests_finish() {
+    let server = start(Server::builder().route("GET", "/slow", slow));
+    let mut client = server.connect();
+    client.send("GET /slow HTTP/1.1\r\n\r\n");
+    thread::sleep(Duration::from_millis(100));
+
+    server.shutdown.shutdown();
+    let reply = client.read_reply(false);
+    assert_eq!(reply.body, "done");
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert_eq!(server.thread.join().unwrap(), Shutdown::Drained);
+}
+
+#[test]
+fn shutdown_gives_up_after_the_grace_period() {
+    let server = start(Server::builder().route("GET", "/slow", slow).shutdown_grace(Duration::from_millis(50)));
+    let mut client = server.connect();
+    client.send("GET /slow HTTP/1.1\r\n\r\n");
+    thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(server.stop(), Shutdown::TimedOut { in_flight: 1 });
+    assert_eq!(client.read_reply(false).body, "done");
+}
+
+#[test]
+fn conditional_writes() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+    let id = client.create("Ann", "ann@example.com");
+    let path = format!("/users/{}", id);
+    let ann = r#"{"name":"Ann","email":"ann@example.com"}"#;
+    let merge = [("Content-Type", "application/merge-patch+json")];
+
+    let updated = client.request_with("PUT", &path, &[("If-Match", "\"1\"")], Some(ann));
+    assert_eq!(updated.status, 200);
+    assert_eq!(updated.header("ETag"), Some("\"2\""));
+
+    let stale = client.request_with("PUT", &path, &[("If-Match", "\"1\"")], Some(ann));
+    assert_eq!(stale.status, 412);
+    assert_eq!(stale.json()["code"], "precondition_failed");
+    let stale = [merge[0], ("If-Match", "\"1\"")];
+    assert_eq!(client.request_with("PATCH", &path, &stale, Some(r#"{"name":"Annie"}"#)).status, 412);
+    assert_eq!(client.request_with("DELETE", &path, &[("If-Match", "W/\"1\", \"9\"")], None).status, 412);
+    assert_eq!(client.request("GET", &path, None).json()["version"], 2);
+
+    let either = [merge[0], ("If-Match", "\"1\", \"2\"")];
+    let patched = client.request_with("PATCH", &path, &either, Some(r#"{"name":"Annie"}"#));
+    assert_eq!(patched.status, 200);
+    assert_eq!(patched.header("ETag"), Some("\"3\""));
+    assert_eq!(client.request_with("DELETE", &path, &[("If-Match", "*")], None).status, 200);
+    assert_eq!(client.request_with("DELETE", &path, &[("If-Match", "\"3\"")], None).status, 404);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn strict_mode_requires_if_match() {
+    let server = start(Server::builder().require_if_match(true));
+    let mut client = server.connect();
+    let id = client.create("Ann", "ann@example.com");
+    let path = format!("/users/{}", id);
+    let ann = r#"{"name":"Annie","email":"ann@example.com"}"#;
+
+    let blind = client.request("PUT", &path, Some(ann));
+    assert_eq!(blind.status, 428);
+    assert_eq!(blind.json()["code"], "precondition_required");
+    let merge = [("Content-Type", "application/merge-patch+json")];
+    assert_eq!(client.request_with("PATCH", &path, &merge, Some(r#"{"name":"Annie"}"#)).status, 428);
+    assert_eq!(client.request("DELETE", &path, None).status, 428);
+    assert_eq!(client.request("GET", &path, None).json()["version"], 1);
+
+    assert_eq!(client.request_with("PUT", &path, &[("If-Match", "*")], Some(ann)).status, 200);
+    assert_eq!(client.request_with("DELETE", &path, &[("If-Match", "\"2\"")], None).status, 200);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+/// Lets another writer rename user `id` between a handler reading it and
+/// writing it back, handing out the copy read before.
+struct RacingRepository {
+    inner: InMemoryUserRepository,
+    rename: Arc<Mutex<Option<i32>>>,
+}
+
+impl UserRepository for RacingRepository {
+    fn create(&self, user: &NewUser, context: &AuditContext) -> Result<User, RepositoryError> {
+        self.inner.create(user, context)
+    }
+
+    fn get(&self, id: i32, include_deleted: bool) -> Result<Option<User>, RepositoryError> {
+        let user = self.inner.get(id, include_deleted)?;
+        if let Some(user) = user.as_ref().filter(|_| self.rename.lock().unwrap().take() == Some(id)) {
+            let renamed = NewUser {
+                name: "Concurrent".to_string(),
+                email: user.email.clone(),
+            };
+            self.inner.update(id, &renamed, None, &AuditContext::system("race".to_string()))?;
+        }
+        Ok(user)
+    }
+
+    fn get_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Option<User>, RepositoryError> {
+        self.inner.get_as_of(id, as_of)
+    }
+
+    fn get_version(&self, id: i32, version: i32) -> Result<Option<User>, RepositoryError> {
+        self.inner.get_version(id, version)
+    }
+
+    fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        self.inner.list(query)
+    }
+
+    fn search(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>, RepositoryError> {
+        self.inner.search(text, limit)
+    }
+
+    fn update(
+        &self,
+        id: i32,
+        user: &NewUser,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.inner.update(id, user, expected, context)
+    }
+
+    fn delete(&self, id: i32, expected: Option<&[i32]>, context: &AuditContext) -> Result<bool, RepositoryError> {
+        self.inner.delete(id, expected, context)
+    }
+
+    fn restore(
+        &self,
+        id: i32,
+        expected: Option<&[i32]>,
+        context: &AuditContext,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.inner.restore(id, expected, context)
+    }
+
+    fn purge_deleted(&self, deleted_before: DateTime<Utc>, context: &AuditContext) -> Result<u64, RepositoryError> {
+        self.inner.purge_deleted(deleted_before, context)
+    }
+
+    fn bulk(
+        &self,
+        operations: &[BulkOperation],
+        atomic: bool,
+        context: &AuditContext,
+    ) -> Result<Vec<BulkResult>, RepositoryError> {
+        self.inner.bulk(operations, atomic, context)
+    }
+
+    fn audit_entries(&self, user_id: Option<i32>, query: &AuditQuery) -> Result<Vec<AuditEntry>, RepositoryError> {
+        self.inner.audit_entries(user_id, query)
+    }
+}
+
+#[test]
+fn patch_refuses_to_overwrite_a_concurrent_write() {
+    let rename = Arc::new(Mutex::new(None));
+    let repository = RacingRepository {
+        inner: InMemoryUserRepository::new(),
+        rename: Arc::clone(&rename),
+    };
+    let (server, _) = start_with_repository(Server::builder(), Box::new(repository));
+    let mut client = server.connect();
+    let id = client.create("Ann", "ann@example.com");
+    let path = format!("/users/{}", id);
+    let merge = [("Content-Type", "application/merge-patch+json")];
+
+    *rename.lock().unwrap() = Some(id as i32);
+    let patched = client.request_with("PATCH", &path, &merge, Some(r#"{"email":"annie@example.com"}"#));
+    assert_eq!(patched.status, 412);
+    let fetched = client.request("GET", &path, None).json();
+    assert_eq!(fetched["name"], "Concurrent");
+    assert_eq!(fetched["email"], "ann@example.com");
+
+    // Retried against the current version, the same patch goes through.
+    let retried = client.request_with("PATCH", &path, &merge, Some(r#"{"email":"annie@example.com"}"#));
+    assert_eq!(retried.status, 200);
+    assert_eq!(retried.json()["name"], "Concurrent");
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+fn statuses(report: &Value) -> Vec<i64> {
+    report["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["status"].as_i64().unwrap())
+        .collect()
+}
+
+#[test]
+fn bulk_requires_versions_in_strict_mode() {
+    let server = start(Server::builder().require_if_match(true));
+    let mut client = server.connect();
+    let ann = client.create("Ann", "ann@example.com");
+    let bob = client.create("Bob", "bob@example.com");
+
+    let batch = serde_json::json!([
+        {"op": "update", "id": ann, "user": {"name": "Blind", "email": "ann@example.com"}},
+        {"op": "delete", "id": bob},
+        {"op": "update", "id": ann, "version": 1, "user": {"name": "Annie", "email": "ann@example.com"}},
+        {"op": "create", "user": {"name": "Cy", "email": "cy@example.com"}}
+    ]);
+    let reply = client.request("POST", "/users/bulk", Some(&batch.to_string()));
+    assert_eq!(reply.status, 207);
+    let report = reply.json();
+    assert_eq!(statuses(&report), vec![428, 428, 200, 200]);
+    assert_eq!(report["results"][0]["error"]["code"], "precondition_required");
+
+    assert_eq!(client.request("GET", &format!("/users/{}", ann), None).json()["name"], "Annie");
+    assert_eq!(client.request("GET", &format!("/users/{}", bob), None).status, 200);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn bulk_reports_every_operation() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+    let ann = client.create("Ann", "ann@example.com");
+
+    let batch = serde_json::json!([
+        {"op": "create", "user": {"name": "Cy", "email": "cy@example.com"}},
+        {"op": "update", "id": ann, "version": 5, "user": {"name": "Annie", "email": "ann@example.com"}},
+        {"op": "delete", "id": 999},
+        {"op": "create", "user": {"name": "Dee", "email": "not-an-email"}}
+    ]);
+    let reply = client.request("POST", "/users/bulk", Some(&batch.to_string()));
+    assert_eq!(reply.status, 207);
+    let report = reply.json();
+    assert_eq!(statuses(&report), vec![200, 412, 404, 422]);
+    assert_eq!((report["succeeded"].as_i64(), report["failed"].as_i64()), (Some(1), Some(3)));
+    assert_eq!(report["results"][0]["user"]["email"], "cy@example.com");
+    assert_eq!(report["results"][3]["error"]["errors"][0]["field"], "user.email");
+    assert_eq!(client.request("GET", "/users?email=cy@example.com", None).json()[0]["name"], "Cy");
+
+    let batch = serde_json::json!([{"op": "delete", "id": ann, "version": 1}]);
+    let reply = client.request("POST", "/users/bulk", Some(&batch.to_string()));
+    assert_eq!(reply.status, 200);
+    assert_eq!(statuses(&reply.json()), vec![200]);
+    assert_eq!(client.request("GET", &format!("/users/{}", ann), None).status, 404);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn atomic_bulk_rolls_back_on_failure() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+    let ann = client.create("Ann", "ann@example.com");
+
+    let batch = serde_json::json!([
+        {"op": "create", "user": {"name": "Cy", "email": "cy@example.com"}},
+        {"op": "update", "id": ann, "version": 1, "user": {"name": "Annie", "email": "ann@example.com"}},
+        {"op": "delete", "id": 999},
+        {"op": "delete", "id": ann}
+    ]);
+    let reply = client.request("POST", "/users/bulk?atomic=true", Some(&batch.to_string()));
+    assert_eq!(reply.status, 207);
+    let report = reply.json();
+    assert_eq!(report["atomic"], true);
+    assert_eq!(statuses(&report), vec![424, 424, 404, 424]);
+    assert_eq!(report["results"][0]["error"]["code"], "batch_aborted");
+    assert_eq!(report["succeeded"], 0);
+
+    assert_eq!(client.request("GET", "/users?email=cy@example.com", None).json(), serde_json::json!([]));
+    let fetched = client.request("GET", &format!("/users/{}", ann), None);
+    assert_eq!(fetched.json()["name"], "Ann");
+    assert_eq!(fetched.header("ETag"), Some("\"1\""));
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn atomic_bulk_with_an_invalid_operation_applies_nothing() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+
+    let batch = serde_json::json!([
+        {"op": "create", "user": {"name": "Cy", "email": "cy@example.com"}},
+        {"op": "rename", "id": 1}
+    ]);
+    let reply = client.request("POST", "/users/bulk?atomic=true", Some(&batch.to_string()));
+    assert_eq!(reply.status, 207);
+    let report = reply.json();
+    assert_eq!(statuses(&report), vec![424, 422]);
+    assert_eq!(report["results"][1]["error"]["code"], "validation_failed");
+    assert_eq!(client.request("GET", "/users", None).json(), serde_json::json!([]));
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn bulk_accepts_ndjson() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+
+    let body = concat!(
+        "{\"op\": \"create\", \"user\": {\"name\": \"Ann\", \"email\": \"ann@example.com\"}}\n",
+        "\n",
+        "{\"op\": \"create\", \"user\": {\"name\": \"Bob\", \"email\": \"bob@example.com\"}}\n",
+    );
+    let headers = [("Content-Type", "application/x-ndjson; charset=utf-8")];
+    let reply = client.request_with("POST", "/users/bulk", &headers, Some(body));
+    assert_eq!(reply.status, 200);
+    assert_eq!(statuses(&reply.json()), vec![200, 200]);
+    assert_eq!(client.request("GET", "/users", None).json().as_array().unwrap().len(), 2);
+
+    let body = "{\"op\": \"delete\", \"id\": 1}\nnot json\n";
+    let reply = client.request_with("POST", "/users/bulk", &headers, Some(body));
+    assert_eq!(reply.status, 207);
+    assert_eq!(statuses(&reply.json()), vec![200, 400]);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn bulk_rejects_empty_and_oversized_batches() {
+    let server = start(Server::builder().max_bulk_operations(2));
+    let mut client = server.connect();
+
+    let create = serde_json::json!({"op": "create", "user": {"name": "Ann", "email": "ann@example.com"}});
+    let batch = serde_json::json!([create, create, create]).to_string();
+    let reply = client.request("POST", "/users/bulk", Some(&batch));
+    assert_eq!(reply.status, 413);
+    assert_eq!(reply.json()["code"], "batch_too_large");
+    assert_eq!(client.request("GET", "/users", None).json(), serde_json::json!([]));
+
+    for body in ["[]", "{\"op\": \"delete\", \"id\": 1}"] {
+        let reply = client.request("POST", "/users/bulk", Some(body));
+        assert_eq!(reply.status, 422, "{:?}", body);
+        assert_eq!(reply.json()["code"], "invalid_payload");
+    }
+    let headers = [("Content-Type", "application/x-ndjson")];
+    assert_eq!(client.request_with("POST", "/users/bulk", &headers, Some("\n\n")).status, 422);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn shutdown_answers_requests_already_sent() {
+    let server = start(Server::builder().workers(1).route("GET", "/slow", slow));
+    let mut busy = server.connect();
+    busy.send("GET /slow HTTP/1.1\r\n\r\n");
+    // Queued behind `busy` on the only worker, its request already written.
+    let mut queued = server.connect();
+    queued.send("POST /users HTTP/1.1\r\nContent-Length: 40\r\n\r\n{\"name\":\"Ann\",\"email\":\"ann@example.com\"}");
+    thread::sleep(Duration::from_millis(100));
+
+    server.shutdown.shutdown();
+    assert_eq!(busy.read_reply(false).body, "done");
+    let created = queued.read_reply(false);
+    assert_eq!(created.status, 200);
+    assert_eq!(created.header("Connection"), Some("close"));
+    assert_eq!(server.thread.join().unwrap(), Shutdown::Drained);
+}
+
+#[test]
+fn soft_delete_restore_and_purge() {
+    let (server, state) = start_with_state(Server::builder());
+    let mut client = server.connect();
+    let ann = client.create("Ann", "ann@example.com");
+    let bob = client.create("Bob", "bob@example.com");
+    let path = format!("/users/{}", ann);
+
+    assert_eq!(client.request("DELETE", &path, None).status, 200);
+    assert_eq!(client.request("GET", &path, None).status, 404);
+    let deleted = client.request("GET", &format!("{}?include_deleted=true", path), None);
+    assert_eq!(deleted.status, 200);
+    assert!(deleted.json()["deleted_at"].is_string());
+    assert_eq!(client.request("GET", "/users", None).json().as_array().unwrap().len(), 1);
+    assert_eq!(client.request("GET", "/users?include_deleted=true", None).json().as_array().unwrap().len(), 2);
+
+    // The email is free again while Ann is deleted, so taking it blocks her restore.
+    let taken = client.create("Ann Two", "ann@example.com");
+    let conflict = client.request("POST", &format!("{}/restore", path), None);
+    assert_eq!(conflict.status, 409);
+    assert_eq!(conflict.json()["code"], "conflict");
+    assert_eq!(client.request("DELETE", &format!("/users/{}", taken), None).status, 200);
+    let restored = client.request("POST", &format!("{}/restore", path), None);
+    assert_eq!(restored.status, 200);
+    assert!(restored.json().get("deleted_at").is_none());
+    assert_eq!(client.request("GET", &path, None).json()["name"], "Ann");
+    let live = client.request("POST", &format!("/users/{}/restore", bob), None);
+    assert_eq!((live.status, live.json()["version"].as_i64()), (200, Some(1)));
+    assert_eq!(client.request("POST", "/users/999/restore", None).status, 404);
+
+    assert_eq!(client.request("DELETE", &path, None).status, 200);
+    let week = RetentionConfig {
+        retention: chrono::Duration::days(7),
+        interval: None,
+    };
+    assert_eq!(purge_expired(state.users.as_ref(), &week).unwrap(), 0);
+    assert_eq!(client.request("GET", &format!("{}?include_deleted=true", path), None).status, 200);
+    let expired = RetentionConfig {
+        retention: chrono::Duration::zero(),
+        interval: None,
+    };
+    assert_eq!(purge_expired(state.users.as_ref(), &expired).unwrap(), 2);
+    assert_eq!(client.request("GET", &format!("{}?include_deleted=true", path), None).status, 404);
+    assert_eq!(client.request("POST", &format!("{}/restore", path), None).status, 404);
+    assert_eq!(client.request("GET", "/users?include_deleted=true", None).json()[0]["id"], bob);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn history_as_of_and_revert() {
+    let server = start(Server::builder());
+    let mut client = server.connect();
+    let id = client.create("Ann", "ann@example.com");
+    let path = format!("/users/{}", id);
+    let as_of = |at: &Value| format!("{}?as_of={}", path, at.as_str().unwrap());
+
+    let created_at = client.request("GET", &path, None).json()["created_at"].clone();
+    thread::sleep(Duration::from_millis(5));
+    let renamed = r#"{"name":"Annie","email":"annie@example.com"}"#;
+    assert_eq!(client.request("PUT", &path, Some(renamed)).status, 200);
+    let renamed_at = client.request("GET", &path, None).json()["updated_at"].clone();
+
+    let then = client.request("GET", &as_of(&created_at), None).json();
+    assert_eq!((then["name"].as_str(), then["version"].as_i64()), (Some("Ann"), Some(1)));
+    assert_eq!(client.request("GET", &as_of(&renamed_at), None).json()["name"], "Annie");
+    let before = client.request("GET", &format!("{}?as_of=2000-01-01T00:00:00Z", path), None);
+    assert_eq!(before.status, 404);
+    assert_eq!(client.request("GET", &format!("{}?as_of=yesterday", path), None).status, 400);
+
+    // Version 1's email now belongs to someone else.
+    let other = client.create("Other", "ann@example.com");
+    let conflict = client.request("POST", &format!("{}/revert?version=1", path), None);
+    assert_eq!(conflict.status, 409);
+    assert_eq!(conflict.json()["field"], "email");
+    assert_eq!(client.request("DELETE", &format!("/users/{}", other), None).status, 200);
+
+    let stale = client.request_with("POST", &format!("{}/revert?version=1", path), &[("If-Match", "\"1\"")], None);
+    assert_eq!(stale.status, 412);
+    let reverted = client.request_with("POST", &format!("{}/revert?version=1", path), &[("If-Match", "\"2\"")], None);
+    assert_eq!(reverted.status, 200);
+    assert_eq!(reverted.header("ETag"), Some("\"3\""));
+    let current = client.request("GET", &path, None).json();
+    assert_eq!((current["name"].as_str(), current["email"].as_str()), (Some("Ann"), Some("ann@example.com")));
+
+    let missing = client.request("POST", &format!("{}/revert?version=9", path), None);
+    assert_eq!(missing.status, 404);
+    assert_eq!(missing.json()["code"], "version_not_found");
+    for query in ["", "?version=0", "?version=abc"] {
+        let reply = client.request("POST", &format!("{}/revert{}", path, query), None);
+        assert_eq!(reply.status, 400, "{:?}", query);
+    }
+    assert_eq!(client.request("POST", "/users/999/revert?version=1", None).status, 404);
+
+    assert_eq!(client.request("DELETE", &path, None).status, 200);
+    let deleted = client.request("GET", &format!("{}?include_deleted=true", path), None).json();
+    let deleted_at = deleted["deleted_at"].clone();
+    assert_eq!(client.request("GET", &as_of(&deleted_at), None).status, 404);
+    let deleted = client.request("GET", &format!("{}&include_deleted=true", as_of(&deleted_at)), None);
+    assert_eq!(deleted.json()["name"], "Ann");
+    assert_eq!(client.request("GET", &as_of(&renamed_at), None).json()["name"], "Annie");
+    assert_eq!(client.request("POST", &format!("{}/revert?version=2", path), None).status, 404);
+    assert_eq!(server.stop(), Shutdown::Drained);
+}
+
+#[test]
+fn purger_stops_with_the_server() {
+    let retention = RetentionConfig {
+        retention: chrono::Duration::zero(),
+        interval: Some(Duration::from_millis(50)),
+    };
+    let (server, state) = start_with_state(Server::builder().purge(retention));
+    let mut client = server.connect();
+    let id = client.create("Ann", "ann@example.com");
+    assert_eq!(client.request("DELETE", &format!("/users/{}", id), None).status, 200);
+    thread::sleep(Duration::from_millis(200));
+    let deleted = client.request("GET", &format!("/users/{}?include_deleted=true", id), None);
+    assert_eq!(deleted.status, 404);
+
+    assert_eq!(server.stop(), Shutdown::Drained);
+    // Neither a worker nor the purger holds on to the state any longer.
+    assert_eq!(Arc::strong_count(&state), 1);
+}